@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::future::{ready, Future};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dashmap::DashMap;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use s3::{LockBackend, ShardedMap, StdMutex, StdRwLock, TokioMutex, TokioRwLock};
+
+
+/*
+
+    the sharded map against a single mutexed map, a single rwlocked map and
+    dashmap, every run spreads the same seeded operations over as many tasks
+    as there are runtime threads, the reconciler of the sharded map is only
+    running in the reconciler group so the other groups only measure the
+    shard locks, `s3 bench` drives the whole map with its reconciler.
+
+*/
+const KEYS: u64 = 10_000;
+const OPS_PER_TASK: usize = 10_000;
+const SHARD_COUNTS: [usize; 5] = [1, 4, 10, 64, 256];
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+/// the percentage of reads of the read only, read mostly and balanced mixes
+const READ_RATIOS: [u32; 3] = [100, 95, 50];
+
+
+/// a map that can be driven by the benchmarks, the sync maps are done by the time their future is returned
+trait Store: Send + Sync + 'static{
+    fn get(&self, key: u64) -> impl Future<Output = ()> + Send;
+    fn insert(&self, key: u64, value: u64) -> impl Future<Output = ()> + Send;
+}
+
+impl<B: LockBackend> Store for ShardedMap<u64, u64, s3::DefaultHashBuilder, B>{
+    async fn get(&self, key: u64){
+        let _ = ShardedMap::get(self, &key).await;
+    }
+
+    async fn insert(&self, key: u64, value: u64){
+        ShardedMap::insert(self, key, value).await.unwrap();
+    }
+}
+
+impl Store for Mutex<HashMap<u64, u64>>{
+    fn get(&self, key: u64) -> impl Future<Output = ()> + Send{
+        let _ = self.lock().unwrap().get(&key).copied();
+        ready(())
+    }
+
+    fn insert(&self, key: u64, value: u64) -> impl Future<Output = ()> + Send{
+        self.lock().unwrap().insert(key, value);
+        ready(())
+    }
+}
+
+impl Store for RwLock<HashMap<u64, u64>>{
+    fn get(&self, key: u64) -> impl Future<Output = ()> + Send{
+        let _ = self.read().unwrap().get(&key).copied();
+        ready(())
+    }
+
+    fn insert(&self, key: u64, value: u64) -> impl Future<Output = ()> + Send{
+        self.write().unwrap().insert(key, value);
+        ready(())
+    }
+}
+
+impl Store for DashMap<u64, u64>{
+    fn get(&self, key: u64) -> impl Future<Output = ()> + Send{
+        let _ = DashMap::get(self, &key).map(|value| *value);
+        ready(())
+    }
+
+    fn insert(&self, key: u64, value: u64) -> impl Future<Output = ()> + Send{
+        DashMap::insert(self, key, value);
+        ready(())
+    }
+}
+
+
+/// the operations of every task, true is a read, generated once so they're not measured
+fn workload(tasks: usize, reads: u32) -> Arc<Vec<Vec<(bool, u64)>>>{
+    let mut rng = ChaCha12Rng::seed_from_u64(42);
+    Arc::new((0..tasks).map(|_| {
+        (0..OPS_PER_TASK).map(|_| (rng.gen_range(0..100) < reads, rng.gen_range(0..KEYS))).collect()
+    }).collect())
+}
+
+fn runtime(threads: usize) -> tokio::runtime::Runtime{
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn sharded<B: LockBackend>(shards: usize) -> Arc<ShardedMap<u64, u64, s3::DefaultHashBuilder, B>>{
+    Arc::new(ShardedMap::<u64, u64>::builder().shards(shards).backend::<B>().build())
+}
+
+/// runs every task of the workload `iters` times over the store and returns the time it took
+fn run<M: Store>(rt: &tokio::runtime::Runtime, store: &Arc<M>, ops: &Arc<Vec<Vec<(bool, u64)>>>, iters: u64) -> Duration{
+    rt.block_on(async{
+        for key in 0..KEYS{
+            store.insert(key, key).await;
+        }
+        let start = Instant::now();
+        for _ in 0..iters{
+            let tasks = (0..ops.len()).map(|task| {
+                let (store, ops) = (store.clone(), ops.clone());
+                tokio::spawn(async move{
+                    for &(read, key) in &ops[task]{
+                        if read{
+                            store.get(key).await;
+                        } else{
+                            store.insert(key, key).await;
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+            for task in tasks{
+                task.await.unwrap();
+            }
+        }
+        start.elapsed()
+    })
+}
+
+
+/// the sharded map with the default tokio mutex backend over the shard counts
+fn shard_counts(c: &mut Criterion){
+    let threads = *THREADS.last().unwrap();
+    let rt = runtime(threads);
+    for reads in READ_RATIOS{
+        let mut group = c.benchmark_group(format!("shard_counts/{}%_reads/{}_threads", reads, threads));
+        let ops = workload(threads, reads);
+        group.throughput(Throughput::Elements((threads * OPS_PER_TASK) as u64));
+        for shards in SHARD_COUNTS{
+            let map = sharded::<TokioMutex>(shards);
+            group.bench_function(BenchmarkId::from_parameter(shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+        }
+        group.finish();
+    }
+}
+
+/// the lock backends of the sharded map over the shard counts
+fn backends(c: &mut Criterion){
+    let threads = *THREADS.last().unwrap();
+    let rt = runtime(threads);
+    for reads in READ_RATIOS{
+        let mut group = c.benchmark_group(format!("backends/{}%_reads/{}_threads", reads, threads));
+        let ops = workload(threads, reads);
+        group.throughput(Throughput::Elements((threads * OPS_PER_TASK) as u64));
+        for shards in SHARD_COUNTS{
+            let map = sharded::<TokioMutex>(shards);
+            group.bench_function(BenchmarkId::new("tokio_mutex", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = sharded::<TokioRwLock>(shards);
+            group.bench_function(BenchmarkId::new("tokio_rwlock", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = sharded::<StdMutex>(shards);
+            group.bench_function(BenchmarkId::new("std_mutex", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = sharded::<StdRwLock>(shards);
+            group.bench_function(BenchmarkId::new("std_rwlock", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            #[cfg(feature = "parking_lot")]
+            {
+                let map = sharded::<s3::ParkingLotMutex>(shards);
+                group.bench_function(BenchmarkId::new("parking_lot_mutex", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+                let map = sharded::<s3::ParkingLotRwLock>(shards);
+                group.bench_function(BenchmarkId::new("parking_lot_rwlock", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            }
+        }
+        group.finish();
+    }
+}
+
+/// the sharded map against the single lock maps and dashmap over the thread counts
+fn maps(c: &mut Criterion){
+    for reads in READ_RATIOS{
+        let mut group = c.benchmark_group(format!("maps/{}%_reads", reads));
+        for threads in THREADS{
+            let rt = runtime(threads);
+            let ops = workload(threads, reads);
+            group.throughput(Throughput::Elements((threads * OPS_PER_TASK) as u64));
+            let map = sharded::<TokioMutex>(64);
+            group.bench_function(BenchmarkId::new("sharded_64_tokio_mutex", threads), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = sharded::<StdRwLock>(64);
+            group.bench_function(BenchmarkId::new("sharded_64_std_rwlock", threads), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = Arc::new(Mutex::new(HashMap::new()));
+            group.bench_function(BenchmarkId::new("mutex_hashmap", threads), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = Arc::new(RwLock::new(HashMap::new()));
+            group.bench_function(BenchmarkId::new("rwlock_hashmap", threads), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = Arc::new(DashMap::new());
+            group.bench_function(BenchmarkId::new("dashmap", threads), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+        }
+        group.finish();
+    }
+}
+
+/// the sharded map with and without its reconciler taking the updates of every write
+fn reconciler(c: &mut Criterion){
+    let threads = *THREADS.last().unwrap();
+    let rt = runtime(threads);
+    for reads in READ_RATIOS{
+        let mut group = c.benchmark_group(format!("reconciler/{}%_reads/{}_threads", reads, threads));
+        let ops = workload(threads, reads);
+        group.throughput(Throughput::Elements((threads * OPS_PER_TASK) as u64));
+        for shards in [10, 64]{
+            let map = sharded::<TokioMutex>(shards);
+            group.bench_function(BenchmarkId::new("without", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            let map = sharded::<TokioMutex>(shards);
+            rt.block_on(async{ map.spawn_reconciler().unwrap() });
+            group.bench_function(BenchmarkId::new("with", shards), |b| b.iter_custom(|iters| run(&rt, &map, &ops, iters)));
+            assert!(rt.block_on(map.shutdown()).is_clean());
+        }
+        group.finish();
+    }
+}
+
+
+fn config() -> Criterion{
+    Criterion::default()
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(2))
+}
+
+criterion_group!{
+    name = benches;
+    config = config();
+    targets = shard_counts, backends, maps, reconciler
+}
+criterion_main!(benches);
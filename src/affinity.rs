@@ -0,0 +1,126 @@
+
+
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use crate::error::{Result, S3Error};
+use crate::guard::ShardGuard;
+use crate::lock::LockBackend;
+use crate::map::ShardedMap;
+
+
+/*
+
+    the part of the key that is hashed to find its home shard, by default
+    it's the whole key but related keys can be put in the same shard either
+    by a redis style hash tag, where only the part between the first `{` and
+    the next `}` of the key gets hashed, or by an affinity function that
+    tells the group of the key, the keys of a group are always at the same
+    home shard (whatever the shard count or the placement is) so they can be
+    updated together under a single shard lock, the affinity is picked once
+    by the builder since changing it would move the keys that are already
+    inside the map away from their home.
+
+*/
+pub enum Affinity<K>{
+    Tag(for<'a> fn(&'a K) -> Option<&'a str>),
+    Group(GroupFn<K>),
+}
+
+/// tells the group of a key, the keys without a group are routed by themselves
+pub type GroupFn<K> = Arc<dyn Fn(&K) -> Option<u64> + Send + Sync>;
+
+impl<K: Hash> Affinity<K>{
+
+    pub(crate) fn hash<S: BuildHasher>(&self, hasher: &S, key: &K) -> u64{
+        match self{
+            Affinity::Tag(tag) => match tag(key){
+                Some(tag) => hasher.hash_one(tag),
+                None => hasher.hash_one(key),
+            },
+            Affinity::Group(group) => match group(key){
+                Some(group) => hasher.hash_one(group),
+                None => hasher.hash_one(key),
+            },
+        }
+    }
+
+}
+
+
+/// the non empty hash tag of the key, `{user:1}:cart` and `{user:1}:orders` share the `user:1` tag
+pub fn hash_tag(key: &str) -> Option<&str>{
+    let start = key.find('{')? + 1;
+    let len = key[start..].find('}')?;
+    (len > 0).then(|| &key[start..start + len])
+}
+
+
+/// the routing of the keys that is picked by the builder of the map
+pub trait Routing<K>{
+    fn affinity(self) -> Option<Affinity<K>>;
+}
+
+/// the whole key is hashed, the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ByKey;
+
+/// the hash tag of the key is hashed, see ShardedMapBuilder::hash_tags()
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ByHashTag;
+
+/// the group of the key is hashed, see ShardedMapBuilder::affinity()
+#[derive(Clone)]
+pub struct ByGroup<F>(pub(crate) F);
+
+impl<K> Routing<K> for ByKey{
+    fn affinity(self) -> Option<Affinity<K>>{
+        None
+    }
+}
+
+impl<K: AsRef<str>> Routing<K> for ByHashTag{
+    fn affinity(self) -> Option<Affinity<K>>{
+        Some(Affinity::Tag(|key| hash_tag(key.as_ref())))
+    }
+}
+
+impl<K, F> Routing<K> for ByGroup<F> where
+    F: Fn(&K) -> Option<u64> + Send + Sync + 'static
+{
+    fn affinity(self) -> Option<Affinity<K>>{
+        Some(Affinity::Group(Arc::new(self.0)))
+    }
+}
+
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /*
+
+        locks the home shard of all the given keys at once so they can be
+        read and updated together atomically, the keys must share the same
+        home shard (see the hash tags and the affinity) otherwise it fails
+        with CrossShard, the returned guard is a writer of the pool like the
+        one of acquire_any_shard(), an empty group fails with EmptyGroup.
+
+    */
+    #[tracing::instrument(level = "trace", skip_all, fields(keys = keys.len(), shard))]
+    pub async fn lock_keys(&self, keys: &[K]) -> Result<ShardGuard<'_, K, V, S, B>>{
+        let (first, rest) = keys.split_first().ok_or(S3Error::EmptyGroup)?;
+        self.ensure_open()?;
+        let permit = self.write_permit().await?;
+        let (index, gaurd) = self.lock_home(first).await?;
+        if rest.iter().any(|key| self.shard_index(key) != index){
+            tracing::debug!("the keys of the group belong to different shards");
+            return Err(S3Error::CrossShard);
+        }
+        Ok(ShardGuard::new(self, index, gaurd, permit))
+    }
+
+}
@@ -0,0 +1,495 @@
+
+
+
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use clap::{Args, ValueEnum};
+use hdrhistogram::Histogram;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use s3::{LockBackend, S3Error, ShardedMap};
+use crate::config::ConfigError;
+
+
+/// the map that is driven by the load generator, the keys are the record ids
+pub type BenchMap<S, B> = ShardedMap<u64, Vec<u8>, S, B>;
+
+
+#[derive(Clone, Debug, Args)]
+pub struct BenchArgs{
+    /// the ycsb workload to run
+    #[arg(long, default_value = "a")]
+    pub workload: Workload,
+
+    /// how the keys are picked [default: latest for d, zipfian for the others]
+    #[arg(long)]
+    pub distribution: Option<Distribution>,
+
+    /// number of records that are loaded before the run
+    #[arg(long, default_value_t = 100_000)]
+    pub records: u64,
+
+    /// total number of operations of the run
+    #[arg(long, default_value_t = 1_000_000)]
+    pub operations: u64,
+
+    /// number of tasks that run the operations concurrently
+    #[arg(long, default_value_t = 16)]
+    pub concurrency: u64,
+
+    /// skew of the zipfian distribution, higher is hotter
+    #[arg(long, default_value_t = 0.99)]
+    pub theta: f64,
+
+    /// max number of records read by a scan
+    #[arg(long, default_value_t = 100)]
+    pub max_scan: u64,
+
+    /// size of the values in bytes
+    #[arg(long, default_value_t = 100)]
+    pub value_size: usize,
+}
+
+impl BenchArgs{
+
+    pub fn validate(&self) -> Result<(), ConfigError>{
+        let invalid = |field, reason| Err(ConfigError::Invalid{field, reason});
+        if self.records == 0{
+            return invalid("records", "at least one record must be loaded");
+        }
+        if self.concurrency == 0{
+            return invalid("concurrency", "at least one task must run the operations");
+        }
+        if !(self.theta > 0.0 && self.theta < 1.0){
+            return invalid("theta", "the zipfian skew must be between 0 and 1");
+        }
+        if self.max_scan == 0{
+            return invalid("max-scan", "a scan must read at least one record");
+        }
+        if self.value_size == 0{
+            return invalid("value-size", "a value must have at least one byte");
+        }
+        Ok(())
+    }
+
+}
+
+
+/*
+
+    the core workloads of ycsb, the proportions are the ones of the ycsb
+    workload files, scans read a run of consecutive record ids since the
+    shards are hashed and have no order to scan.
+
+        a: update heavy, 50% reads and 50% updates
+        b: read mostly, 95% reads and 5% updates
+        c: read only
+        d: read latest, 95% reads and 5% inserts of the newest records
+        e: short ranges, 95% scans and 5% inserts
+        f: read-modify-write, 50% reads and 50% read-modify-writes
+
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Workload{
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+}
+
+impl Workload{
+
+    /// the percentages of the reads, updates, inserts, scans and read-modify-writes
+    fn mix(self) -> [(Op, u32); 5]{
+        let (read, update, insert, scan, rmw) = match self{
+            Workload::A => (50, 50, 0, 0, 0),
+            Workload::B => (95, 5, 0, 0, 0),
+            Workload::C => (100, 0, 0, 0, 0),
+            Workload::D => (95, 0, 5, 0, 0),
+            Workload::E => (0, 0, 5, 95, 0),
+            Workload::F => (50, 0, 0, 0, 50),
+        };
+        [(Op::Read, read), (Op::Update, update), (Op::Insert, insert), (Op::Scan, scan), (Op::ReadModifyWrite, rmw)]
+    }
+
+    fn distribution(self) -> Distribution{
+        match self{
+            Workload::D => Distribution::Latest,
+            _ => Distribution::Zipfian,
+        }
+    }
+
+    fn pick(self, rng: &mut ChaCha12Rng) -> Op{
+        let mut dice = rng.gen_range(0..100);
+        for (op, percent) in self.mix(){
+            if dice < percent{
+                return op;
+            }
+            dice -= percent;
+        }
+        Op::Read
+    }
+
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Distribution{
+    /// every record is as likely as the others
+    Uniform,
+    /// a few records get most of the operations, they're spread over the key space
+    Zipfian,
+    /// the newest records get most of the operations
+    Latest,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op{
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Op{
+
+    const ALL: [Op; 5] = [Op::Read, Op::Update, Op::Insert, Op::Scan, Op::ReadModifyWrite];
+
+    fn index(self) -> usize{
+        self as usize
+    }
+
+}
+
+impl fmt::Display for Op{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let name = match self{
+            Op::Read => "read",
+            Op::Update => "update",
+            Op::Insert => "insert",
+            Op::Scan => "scan",
+            Op::ReadModifyWrite => "rmw",
+        };
+        f.pad(name)
+    }
+}
+
+
+/*
+
+    the zipfian generator of gray et al. (quickly generating billion-record
+    synthetic databases) that ycsb uses, it gives the rank of an item where
+    rank 0 is the hottest one, the zeta constant is computed once for the
+    loaded records, the ranks are scrambled into record ids so the hot
+    records don't all sit next to each other.
+
+*/
+struct Zipfian{
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian{
+
+    fn new(items: u64, theta: f64) -> Self{
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(items);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan);
+        Self{items, theta, alpha: 1.0 / (1.0 - theta), zetan, eta}
+    }
+
+    fn rank(&self, rng: &mut ChaCha12Rng) -> u64{
+        let u = rng.gen::<f64>();
+        let uz = u * self.zetan;
+        if uz < 1.0{
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta){
+            return 1;
+        }
+        ((self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64).min(self.items - 1)
+    }
+
+}
+
+
+/// picks the record ids out of the records that have been inserted so far
+struct Chooser{
+    distribution: Distribution,
+    zipfian: Arc<Zipfian>,
+    inserted: Arc<AtomicU64>,
+}
+
+impl Chooser{
+
+    fn key(&self, rng: &mut ChaCha12Rng) -> u64{
+        let inserted = self.inserted.load(Ordering::Relaxed).max(1);
+        match self.distribution{
+            Distribution::Uniform => rng.gen_range(0..inserted),
+            Distribution::Zipfian => scramble(self.zipfian.rank(rng)) % inserted,
+            Distribution::Latest => inserted - 1 - self.zipfian.rank(rng).min(inserted - 1),
+        }
+    }
+
+}
+
+/// the fnv-1a hash of the rank, the same scrambling as the ycsb scrambled zipfian
+fn scramble(rank: u64) -> u64{
+    rank.to_le_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+
+/// the latencies of every kind of operation in nanoseconds
+struct Latencies{
+    ops: Vec<Histogram<u64>>,
+}
+
+impl Latencies{
+
+    fn new() -> Self{
+        Self{ops: Op::ALL.iter().map(|_| Histogram::new(3).expect("3 significant figures are allowed")).collect()}
+    }
+
+    fn record(&mut self, op: Op, latency: Duration){
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        //// the histograms grow to fit the slowest operation
+        let _ = self.ops[op.index()].record(nanos);
+    }
+
+    fn merge(&mut self, other: &Latencies){
+        for (mine, theirs) in self.ops.iter_mut().zip(&other.ops){
+            //// both of them auto resize so adding never fails
+            let _ = mine.add(theirs);
+        }
+    }
+
+}
+
+
+/*
+
+    loads the records and then runs the operations of the workload over
+    `concurrency` tasks, every task gets its own rng seeded from the given
+    one so a seeded run always generates the same operations, the latency
+    of each operation is recorded from its start to its end.
+
+*/
+pub async fn run<S, B>(args: &BenchArgs, mut rng: ChaCha12Rng, map: BenchMap<S, B>) -> Result<(), S3Error> where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+    let map = Arc::new(map);
+    map.spawn_reconciler()?;
+
+    let started = Instant::now();
+    let loaders = (0..args.concurrency).map(|task| {
+        let (map, mut rng, args) = (map.clone(), ChaCha12Rng::seed_from_u64(rng.gen()), args.clone());
+        tokio::spawn(async move{
+            for key in (task..args.records).step_by(args.concurrency as usize){
+                map.insert(key, value(&mut rng, args.value_size)).await?;
+            }
+            Ok::<_, S3Error>(())
+        })
+    }).collect::<Vec<_>>();
+    for loader in loaders{
+        loader.await.expect("a loader has panicked")?;
+    }
+    println!("loaded {} records in {:.2?}", args.records, started.elapsed());
+
+    let inserted = Arc::new(AtomicU64::new(args.records));
+    let distribution = args.distribution.unwrap_or(args.workload.distribution());
+    let zipfian = Arc::new(Zipfian::new(args.records, args.theta));
+
+    let started = Instant::now();
+    let workers = (0..args.concurrency).map(|task| {
+        let (map, mut rng, args) = (map.clone(), ChaCha12Rng::seed_from_u64(rng.gen()), args.clone());
+        let chooser = Chooser{distribution, zipfian: zipfian.clone(), inserted: inserted.clone()};
+        //// the first tasks take the remainder
+        let operations = args.operations / args.concurrency + u64::from(task < args.operations % args.concurrency);
+        tokio::spawn(async move{
+            let mut latencies = Latencies::new();
+            for _ in 0..operations{
+                let op = args.workload.pick(&mut rng);
+                let start = Instant::now();
+                match op{
+                    Op::Read => {
+                        found(map.get(&chooser.key(&mut rng)).await)?;
+                    },
+                    Op::Update => {
+                        map.insert(chooser.key(&mut rng), value(&mut rng, args.value_size)).await?;
+                    },
+                    Op::Insert => {
+                        let key = chooser.inserted.fetch_add(1, Ordering::Relaxed);
+                        map.insert(key, value(&mut rng, args.value_size)).await?;
+                    },
+                    Op::Scan => {
+                        let first = chooser.key(&mut rng);
+                        for key in first..first + rng.gen_range(1..=args.max_scan){
+                            found(map.get(&key).await)?;
+                        }
+                    },
+                    Op::ReadModifyWrite => {
+                        let key = chooser.key(&mut rng);
+                        let mut value = found(map.get(&key).await)?.unwrap_or_default();
+                        value.resize(args.value_size, 0);
+                        value[0] = value[0].wrapping_add(1);
+                        map.insert(key, value).await?;
+                    },
+                }
+                latencies.record(op, start.elapsed());
+            }
+            Ok::<_, S3Error>(latencies)
+        })
+    }).collect::<Vec<_>>();
+
+    let mut latencies = Latencies::new();
+    for worker in workers{
+        latencies.merge(&worker.await.expect("a worker has panicked")?);
+    }
+    let elapsed = started.elapsed();
+    map.shutdown().await;
+
+    report(args, distribution, elapsed, &latencies);
+    Ok(())
+}
+
+/// a record that is being inserted by another task may not be there yet
+fn found<V>(result: Result<V, S3Error>) -> Result<Option<V>, S3Error>{
+    match result{
+        Ok(value) => Ok(Some(value)),
+        Err(S3Error::KeyNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn value(rng: &mut ChaCha12Rng, size: usize) -> Vec<u8>{
+    let mut value = vec![0; size];
+    rng.fill(&mut value[..]);
+    value
+}
+
+fn report(args: &BenchArgs, distribution: Distribution, elapsed: Duration, latencies: &Latencies){
+    let total = latencies.ops.iter().map(Histogram::len).sum::<u64>();
+    println!(
+        "workload {:?} ({:?}): {} operations over {} tasks in {:.2?}, {:.0} ops/s",
+        args.workload, distribution, total, args.concurrency, elapsed, total as f64 / elapsed.as_secs_f64()
+    );
+    println!("{:<8} {:>10} {:>12} {:>12} {:>12}", "op", "count", "p50", "p99", "p999");
+    for op in Op::ALL{
+        let histogram = &latencies.ops[op.index()];
+        if histogram.is_empty(){
+            continue;
+        }
+        println!(
+            "{:<8} {:>10} {:>12} {:>12} {:>12}",
+            op,
+            histogram.len(),
+            micros(histogram.value_at_quantile(0.5)),
+            micros(histogram.value_at_quantile(0.99)),
+            micros(histogram.value_at_quantile(0.999)),
+        );
+    }
+}
+
+fn micros(nanos: u64) -> String{
+    format!("{:.1}µs", nanos as f64 / 1e3)
+}
+
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    const DRAWS: usize = 100_000;
+
+    fn rng() -> ChaCha12Rng{
+        ChaCha12Rng::seed_from_u64(42)
+    }
+
+    fn chooser(distribution: Distribution, inserted: u64) -> Chooser{
+        Chooser{distribution, zipfian: Arc::new(Zipfian::new(inserted, 0.99)), inserted: Arc::new(AtomicU64::new(inserted))}
+    }
+
+    #[test]
+    fn every_workload_mix_adds_up_and_is_what_gets_picked(){
+        let mut rng = rng();
+        for &workload in Workload::value_variants(){
+            let mix = workload.mix();
+            assert_eq!(mix.iter().map(|(_, percent)| percent).sum::<u32>(), 100, "workload {:?}", workload);
+
+            let mut picked = [0usize; 5];
+            for _ in 0..DRAWS{
+                picked[workload.pick(&mut rng).index()] += 1;
+            }
+            for (op, percent) in mix{
+                let share = picked[op.index()] as f64 * 100.0 / DRAWS as f64;
+                assert!((share - percent as f64).abs() < 1.0, "workload {:?} picked {} {:.1}% of the time instead of {}%", workload, op, share, percent);
+            }
+        }
+    }
+
+    #[test]
+    fn zipfian_ranks_stay_in_range_and_the_first_one_is_the_hottest(){
+        let mut rng = rng();
+        for items in [2, 3, 10, 1_000]{
+            let zipfian = Zipfian::new(items, 0.99);
+            let mut drawn = vec![0usize; items as usize];
+            for _ in 0..DRAWS{
+                let rank = zipfian.rank(&mut rng);
+                assert!(rank < items, "rank {} out of {} items", rank, items);
+                drawn[rank as usize] += 1;
+            }
+            let hottest = (0..drawn.len()).max_by_key(|&rank| drawn[rank]).unwrap();
+            assert_eq!(hottest, 0, "{} items: {:?}", items, &drawn[..drawn.len().min(10)]);
+        }
+    }
+
+    #[test]
+    fn the_choosers_stay_within_the_inserted_records(){
+        let mut rng = rng();
+        for distribution in [Distribution::Uniform, Distribution::Zipfian, Distribution::Latest]{
+            let chooser = chooser(distribution, 1_000);
+            assert!((0..DRAWS).all(|_| chooser.key(&mut rng) < 1_000), "{:?}", distribution);
+        }
+
+        // the uniform one spreads its keys evenly over the records
+        let chooser = chooser(Distribution::Uniform, 1_000);
+        let mut tenths = [0usize; 10];
+        for _ in 0..DRAWS{
+            tenths[chooser.key(&mut rng) as usize / 100] += 1;
+        }
+        assert!(tenths.iter().all(|&drawn| drawn.abs_diff(DRAWS / 10) < DRAWS / 100), "{:?}", tenths);
+    }
+
+    #[test]
+    fn latest_favours_the_newest_records(){
+        let mut rng = rng();
+        let chooser = chooser(Distribution::Latest, 1_000);
+        let mut drawn = vec![0usize; 1_000];
+        for _ in 0..DRAWS{
+            drawn[chooser.key(&mut rng) as usize] += 1;
+        }
+        assert_eq!((0..drawn.len()).max_by_key(|&key| drawn[key]), Some(999));
+        let newest = drawn[900..].iter().sum::<usize>();
+        let oldest = drawn[..100].iter().sum::<usize>();
+        assert!(newest > DRAWS / 2 && newest > 10 * oldest, "newest {} oldest {}", newest, oldest);
+
+        // a new record becomes the newest one right away
+        chooser.inserted.store(1_001, Ordering::Relaxed);
+        let latest = (0..DRAWS).filter(|_| chooser.key(&mut rng) == 1_000).count();
+        assert!(latest > DRAWS / 10, "{}", latest);
+    }
+
+}
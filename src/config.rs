@@ -0,0 +1,414 @@
+
+
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::Deserialize;
+use s3::{Placement, ShardedMapBuilder};
+use crate::bench::BenchArgs;
+use crate::simulate::SimArgs;
+
+
+/*
+
+    the command line of the s3 binary, every flag can also be given by its
+    S3_* env var, the flags and the env vars win over the toml config file
+    which wins over the defaults, so a config file can be shared by a fleet
+    and tweaked per node, the demo runs when there is no subcommand.
+
+*/
+#[derive(Debug, Parser)]
+#[command(name = "s3", version, about = "sharded shared state demo")]
+pub struct Cli{
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// toml config file
+    #[arg(long, global = true, env = "S3_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// number of shards [default: 10]
+    #[arg(long, global = true, env = "S3_SHARDS")]
+    pub shards: Option<usize>,
+
+    /// capacity of the mpsc updates queue [default: the shard count]
+    #[arg(long, global = true, env = "S3_UPDATES_CAPACITY")]
+    pub updates_capacity: Option<usize>,
+
+    /// capacity of the broadcast channel of the merged data [default: the shard count]
+    #[arg(long, global = true, env = "S3_PUBLISH_CAPACITY")]
+    pub publish_capacity: Option<usize>,
+
+    /// max number of updates the reconciler merges at once [default: 64]
+    #[arg(long, global = true, env = "S3_BATCH_SIZE")]
+    pub batch_size: Option<usize>,
+
+    /// max number of writers admitted at the same time [default: 1024]
+    #[arg(long, global = true, env = "S3_MAX_WRITERS")]
+    pub max_writers: Option<u32>,
+
+    /// hasher of the keys [default: fx]
+    #[arg(long, global = true, env = "S3_HASHER")]
+    pub hasher: Option<Hasher>,
+
+    /// placement of the keys on the shards [default: modulo]
+    #[arg(long, global = true, env = "S3_PLACEMENT")]
+    pub placement: Option<PlacementKind>,
+
+    /// virtual nodes per shard of the ring placement [default: 160]
+    #[arg(long, global = true, env = "S3_VNODES")]
+    pub vnodes: Option<usize>,
+
+    /// lock backend of the shards [default: tokio-mutex]
+    #[arg(long, global = true, env = "S3_BACKEND")]
+    pub backend: Option<Backend>,
+
+    /// seed of the key generator and the first seed of the simulation, a random one is used if it's not given
+    #[arg(long, global = true, env = "S3_SEED")]
+    pub seed: Option<u64>,
+
+    /// publish a lock free view of a shard on every write
+    #[arg(long, global = true, env = "S3_COPY_ON_WRITE")]
+    pub copy_on_write: Option<bool>,
+
+    /// number of hot keys to track [default: 0]
+    #[arg(long, global = true, env = "S3_HOT_KEYS")]
+    pub hot_keys: Option<usize>,
+
+    /// file the map is loaded from on start and saved to on shutdown
+    #[arg(long, global = true, env = "S3_SNAPSHOT")]
+    pub snapshot: Option<PathBuf>,
+
+    /// address of the prometheus metrics endpoint, needs the prometheus feature
+    #[arg(long, global = true, env = "S3_METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command{
+    /// drives the map with a ycsb style workload and prints its throughput and latencies
+    Bench(BenchArgs),
+    /// replays seeded runs of the map on a deterministic executor and checks that no update gets lost
+    Sim(SimArgs),
+}
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Hasher{
+    /// FxHash, fast but not dos resistant
+    #[default]
+    Fx,
+    /// the SipHash of the std HashMap, randomly keyed
+    Sip,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlacementKind{
+    #[default]
+    Modulo,
+    Ring,
+    Rendezvous,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend{
+    #[default]
+    TokioMutex,
+    TokioRwlock,
+    StdMutex,
+    StdRwlock,
+    ParkingLotMutex,
+    ParkingLotRwlock,
+}
+
+
+/// the settings of the binary, the missing keys of the config file fall back to the defaults
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config{
+    pub shards: usize,
+    pub updates_capacity: Option<usize>,
+    pub publish_capacity: Option<usize>,
+    pub batch_size: usize,
+    pub max_writers: u32,
+    pub hasher: Hasher,
+    pub placement: PlacementKind,
+    pub vnodes: usize,
+    pub backend: Backend,
+    pub seed: Option<u64>,
+    pub copy_on_write: bool,
+    pub hot_keys: usize,
+    pub snapshot: Option<PathBuf>,
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config{
+    fn default() -> Self{
+        Self{
+            shards: 10,
+            updates_capacity: None,
+            publish_capacity: None,
+            batch_size: 64,
+            max_writers: 1024,
+            hasher: Hasher::Fx,
+            placement: PlacementKind::Modulo,
+            vnodes: 160,
+            backend: Backend::TokioMutex,
+            seed: None,
+            copy_on_write: false,
+            hot_keys: 0,
+            snapshot: None,
+            metrics_addr: None,
+        }
+    }
+}
+
+impl Config{
+
+    /// the defaults, overridden by the config file, overridden by the env vars and the flags
+    pub fn load(cli: Cli) -> Result<Self, ConfigError>{
+        let mut config = match &cli.config{
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read{path: path.clone(), reason: e.to_string()})?;
+                toml::from_str::<Config>(&content)
+                    .map_err(|e| ConfigError::Parse{path: path.clone(), reason: e.message().to_string()})?
+            },
+            None => Config::default(),
+        };
+
+        let Cli{
+            command: _, config: _, shards, updates_capacity, publish_capacity, batch_size, max_writers,
+            hasher, placement, vnodes, backend, seed, copy_on_write, hot_keys, snapshot, metrics_addr,
+        } = cli;
+        config.shards = shards.unwrap_or(config.shards);
+        config.updates_capacity = updates_capacity.or(config.updates_capacity);
+        config.publish_capacity = publish_capacity.or(config.publish_capacity);
+        config.batch_size = batch_size.unwrap_or(config.batch_size);
+        config.max_writers = max_writers.unwrap_or(config.max_writers);
+        config.hasher = hasher.unwrap_or(config.hasher);
+        config.placement = placement.unwrap_or(config.placement);
+        config.vnodes = vnodes.unwrap_or(config.vnodes);
+        config.backend = backend.unwrap_or(config.backend);
+        config.seed = seed.or(config.seed);
+        config.copy_on_write = copy_on_write.unwrap_or(config.copy_on_write);
+        config.hot_keys = hot_keys.unwrap_or(config.hot_keys);
+        config.snapshot = snapshot.or(config.snapshot);
+        config.metrics_addr = metrics_addr.or(config.metrics_addr);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// the values that the map would panic on or that this build can't serve
+    pub fn validate(&self) -> Result<(), ConfigError>{
+        let invalid = |field, reason| Err(ConfigError::Invalid{field, reason});
+        if self.shards == 0{
+            return invalid("shards", "a sharded map needs at least one shard");
+        }
+        if self.updates_capacity == Some(0) || self.publish_capacity == Some(0){
+            return invalid("capacity", "a channel must be able to hold at least one message");
+        }
+        if self.batch_size == 0{
+            return invalid("batch-size", "the reconciler must merge at least one update at once");
+        }
+        if self.max_writers == 0{
+            return invalid("max-writers", "at least one writer must be admitted");
+        }
+        if self.placement == PlacementKind::Ring && self.vnodes == 0{
+            return invalid("vnodes", "a shard needs at least one virtual node on the ring");
+        }
+        if cfg!(not(feature = "parking_lot")) && matches!(self.backend, Backend::ParkingLotMutex | Backend::ParkingLotRwlock){
+            return invalid("backend", "the binary has been built without the parking_lot feature");
+        }
+        if cfg!(not(feature = "prometheus")) && self.metrics_addr.is_some(){
+            return invalid("metrics-addr", "the binary has been built without the prometheus feature");
+        }
+        Ok(())
+    }
+
+    /// the generator of the keys, seeded by the config if it has a seed
+    pub fn rng(&self) -> ChaCha12Rng{
+        match self.seed{
+            Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+            None => ChaCha12Rng::from_entropy(),
+        }
+    }
+
+    /// a builder with everything but the hasher and the lock backend
+    pub fn builder(&self) -> ShardedMapBuilder{
+        let placement = match self.placement{
+            PlacementKind::Modulo => Placement::Modulo,
+            PlacementKind::Ring => Placement::ring(self.vnodes),
+            PlacementKind::Rendezvous => Placement::Rendezvous,
+        };
+        let mut builder = ShardedMapBuilder::new()
+            .shards(self.shards)
+            .batch_size(self.batch_size)
+            .max_writers(self.max_writers)
+            .placement(placement)
+            .copy_on_write(self.copy_on_write)
+            .hot_keys(self.hot_keys);
+        if let Some(capacity) = self.updates_capacity{
+            builder = builder.updates_capacity(capacity);
+        }
+        if let Some(capacity) = self.publish_capacity{
+            builder = builder.publish_capacity(capacity);
+        }
+        builder
+    }
+
+}
+
+
+/// everything that can be wrong with the configuration of the binary
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError{
+    Read{path: PathBuf, reason: String},
+    Parse{path: PathBuf, reason: String},
+    Invalid{field: &'static str, reason: &'static str},
+}
+
+impl fmt::Display for ConfigError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            ConfigError::Read{path, reason} => write!(f, "can't read the config file {}: {}", path.display(), reason),
+            ConfigError::Parse{path, reason} => write!(f, "invalid config file {}: {}", path.display(), reason.trim_end()),
+            ConfigError::Invalid{field, reason} => write!(f, "invalid {}: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError{}
+
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+    use crate::simulate::SimArgs;
+
+    /// clap reads the S3_* env vars on every parse, so the tests that parse or touch the env take turns
+    fn env() -> MutexGuard<'static, ()>{
+        static ENV: Mutex<()> = Mutex::new(());
+        ENV.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn load(args: &[&str]) -> Result<Config, ConfigError>{
+        Config::load(Cli::try_parse_from(std::iter::once("s3").chain(args.iter().copied())).unwrap())
+    }
+
+    fn invalid(config: Config) -> Option<&'static str>{
+        match config.validate(){
+            Err(ConfigError::Invalid{field, ..}) => Some(field),
+            _ => None,
+        }
+    }
+
+    /// the only test that changes the env, the others only read it while it's not being changed
+    #[test]
+    fn flags_win_over_env_vars_which_win_over_the_file_which_wins_over_the_defaults(){
+        let _env = env();
+        let path = std::env::temp_dir().join(format!("s3-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "shards = 16\nbatch-size = 8\nmax-writers = 4\nhasher = \"sip\"\n").unwrap();
+        let file = path.to_str().unwrap();
+
+        std::env::remove_var("S3_SHARDS");
+        std::env::remove_var("S3_BATCH_SIZE");
+        assert_eq!(load(&[]), Ok(Config::default()));
+
+        let config = load(&["--config", file]).unwrap();
+        assert_eq!((config.shards, config.batch_size, config.max_writers, config.hasher), (16, 8, 4, Hasher::Sip));
+        assert_eq!(config.vnodes, Config::default().vnodes);
+
+        std::env::set_var("S3_SHARDS", "32");
+        std::env::set_var("S3_BATCH_SIZE", "2");
+        let config = load(&["--config", file]);
+        let flagged = load(&["--config", file, "--shards", "64", "--hasher", "fx"]);
+        std::env::remove_var("S3_SHARDS");
+        std::env::remove_var("S3_BATCH_SIZE");
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!((config.shards, config.batch_size, config.max_writers, config.hasher), (32, 2, 4, Hasher::Sip));
+        let flagged = flagged.unwrap();
+        assert_eq!((flagged.shards, flagged.batch_size, flagged.max_writers, flagged.hasher), (64, 2, 4, Hasher::Fx));
+    }
+
+    #[test]
+    fn unreadable_and_malformed_files_are_errors(){
+        let _env = env();
+        let missing = std::env::temp_dir().join("s3-config-that-does-not-exist.toml");
+        assert!(matches!(load(&["--config", missing.to_str().unwrap()]), Err(ConfigError::Read{..})));
+
+        let path = std::env::temp_dir().join(format!("s3-config-{}-malformed.toml", std::process::id()));
+        std::fs::write(&path, "shards = 16\ncolour = \"blue\"\n").unwrap();
+        let loaded = load(&["--config", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Err(ConfigError::Parse{reason, ..}) if reason.contains("colour")));
+    }
+
+    #[test]
+    fn every_invalid_setting_is_rejected(){
+        let config = Config::default;
+        assert_eq!(invalid(config()), None);
+        assert_eq!(invalid(Config{shards: 0, ..config()}), Some("shards"));
+        assert_eq!(invalid(Config{updates_capacity: Some(0), ..config()}), Some("capacity"));
+        assert_eq!(invalid(Config{publish_capacity: Some(0), ..config()}), Some("capacity"));
+        assert_eq!(invalid(Config{batch_size: 0, ..config()}), Some("batch-size"));
+        assert_eq!(invalid(Config{max_writers: 0, ..config()}), Some("max-writers"));
+        assert_eq!(invalid(Config{placement: PlacementKind::Ring, vnodes: 0, ..config()}), Some("vnodes"));
+        assert_eq!(invalid(Config{placement: PlacementKind::Modulo, vnodes: 0, ..config()}), None);
+
+        let parking_lot = invalid(Config{backend: Backend::ParkingLotRwlock, ..config()});
+        assert_eq!(parking_lot, if cfg!(feature = "parking_lot"){ None } else{ Some("backend") });
+        let metrics = invalid(Config{metrics_addr: Some(SocketAddr::from(([127, 0, 0, 1], 9090))), ..config()});
+        assert_eq!(metrics, if cfg!(feature = "prometheus"){ None } else{ Some("metrics-addr") });
+    }
+
+    #[test]
+    fn every_invalid_subcommand_argument_is_rejected(){
+        let _env = env();
+        let bench = |args: &[&str]| match Cli::try_parse_from(["s3", "bench"].iter().chain(args)).unwrap().command{
+            Some(Command::Bench(bench)) => match bench.validate(){
+                Err(ConfigError::Invalid{field, ..}) => Some(field),
+                _ => None,
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(bench(&[]), None);
+        assert_eq!(bench(&["--records", "0"]), Some("records"));
+        assert_eq!(bench(&["--concurrency", "0"]), Some("concurrency"));
+        assert_eq!(bench(&["--theta", "1.5"]), Some("theta"));
+        assert_eq!(bench(&["--max-scan", "0"]), Some("max-scan"));
+        assert_eq!(bench(&["--value-size", "0"]), Some("value-size"));
+
+        let sim = |args: &[&str], config: Config| match Cli::try_parse_from(["s3", "sim"].iter().chain(args)).unwrap().command{
+            Some(Command::Sim(sim)) => match SimArgs::validate(&sim, &config){
+                Err(ConfigError::Invalid{field, ..}) => Some(field),
+                _ => None,
+            },
+            _ => unreachable!(),
+        };
+        let config = Config::default;
+        assert_eq!(sim(&[], config()), None);
+        assert_eq!(sim(&["--seeds", "0"], config()), Some("seeds"));
+        assert_eq!(sim(&["--writers", "0"], config()), Some("writers"));
+        assert_eq!(sim(&["--keys", "0"], config()), Some("keys"));
+        assert_eq!(sim(&["--operations", "5000000000"], config()), Some("operations"));
+        assert_eq!(sim(&["--writers", "65536", "--keys", "65536"], config()), Some("operations"));
+        assert_eq!(sim(&[], Config{hasher: Hasher::Sip, ..config()}), Some("hasher"));
+        assert_eq!(sim(&[], Config{backend: Backend::StdMutex, ..config()}), Some("backend"));
+        assert_eq!(sim(&[], Config{backend: Backend::TokioRwlock, ..config()}), None);
+    }
+
+}
@@ -0,0 +1,315 @@
+
+
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::error::{Result, S3Error};
+use crate::map::DefaultHashBuilder;
+use crate::version::{HybridClock, Version, Versioned};
+
+
+/// a last writer wins register, the value with the highest version is kept
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LwwRegister<V>{
+    value: V,
+    version: Version,
+}
+
+impl<V: Clone> LwwRegister<V>{
+
+    pub fn new(value: V, version: Version) -> Self{
+        Self{value, version}
+    }
+
+    pub fn value(&self) -> &V{
+        &self.value
+    }
+
+    pub fn version(&self) -> Version{
+        self.version
+    }
+
+    pub fn merge(&mut self, other: &Self){
+        if other.version > self.version{
+            *self = other.clone();
+        }
+    }
+
+}
+
+/*
+
+    the state of a single key inside an or-map, every add is tagged with
+    the version of the write (versions are unique per clock so they can be
+    used as the add tags) and carries its own value, a remove tombstones all
+    the tags that it has observed and drops their values, the key is alive
+    as long as there is an add which has not been removed yet and its value
+    is the one of the highest alive add, so a remove never wins against a
+    concurrent add that it hasn't seen and never brings back a removed value.
+
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct OrEntry<V>{
+    adds: BTreeMap<Version, LwwRegister<V>>,
+    removes: BTreeSet<Version>,
+}
+
+impl<V: Clone> OrEntry<V>{
+
+    fn new() -> Self{
+        Self{adds: BTreeMap::new(), removes: BTreeSet::new()}
+    }
+
+    /// the alive add with the highest version
+    fn register(&self) -> Option<&LwwRegister<V>>{
+        self.adds.values().next_back()
+    }
+
+    fn is_alive(&self) -> bool{
+        !self.adds.is_empty()
+    }
+
+    fn add(&mut self, register: LwwRegister<V>){
+        if !self.removes.contains(&register.version){
+            self.adds.insert(register.version, register);
+        }
+    }
+
+    fn merge(&mut self, other: &Self){
+        self.removes.extend(other.removes.iter().copied());
+        for register in other.adds.values(){
+            self.add(register.clone());
+        }
+        let removes = &self.removes;
+        self.adds.retain(|tag, _| !removes.contains(tag));
+    }
+
+}
+
+
+/*
+
+    an observed-remove map of lww registers, merging two maps is a union of
+    the add and remove tags of every key minus the adds that have been removed
+    so it's commutative, associative and idempotent and replicas converge no
+    matter in which order they've been merged, removed keys are kept as
+    tombstones to be able to tell them apart from the keys that have never
+    been seen.
+
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrMap<K: Eq + Hash, V>{
+    entries: HashMap<K, OrEntry<V>>,
+}
+
+impl<K: Eq + Hash, V> Default for OrMap<K, V>{
+    fn default() -> Self{
+        Self{entries: HashMap::new()}
+    }
+}
+
+impl<K, V> OrMap<K, V> where
+    K: Eq + Hash + Clone,
+    V: Clone
+{
+
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    /// puts the value with the given version, the version must be unique for every write
+    pub fn insert(&mut self, key: K, value: V, version: Version){
+        self.entries.entry(key)
+            .or_insert_with(OrEntry::new)
+            .add(LwwRegister::new(value, version));
+    }
+
+    /// tombstones every add of the key that this replica has observed
+    pub fn remove(&mut self, key: &K) -> Option<V>{
+        let entry = self.entries.get_mut(key)?;
+        let removed = entry.register().map(|register| register.value.clone());
+        let observed = std::mem::take(&mut entry.adds);
+        entry.removes.extend(observed.into_keys());
+        removed
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V>{
+        self.get_register(key).map(LwwRegister::value)
+    }
+
+    pub fn get_register(&self, key: &K) -> Option<&LwwRegister<V>>{
+        self.entries.get(key).and_then(OrEntry::register)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool{
+        self.get_register(key).is_some()
+    }
+
+    /// number of alive keys
+    pub fn len(&self) -> usize{
+        self.entries.values().filter(|entry| entry.is_alive()).count()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+
+    /// the alive keys along with their registers
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &LwwRegister<V>)>{
+        self.entries.iter()
+            .filter_map(|(key, entry)| entry.register().map(|register| (key, register)))
+    }
+
+    pub fn merge(&mut self, other: &Self){
+        for (key, entry) in &other.entries{
+            self.merge_entry(key, entry);
+        }
+    }
+
+    fn merge_entry(&mut self, key: &K, entry: &OrEntry<V>){
+        match self.entries.get_mut(key){
+            Some(current) => current.merge(entry),
+            None => {
+                self.entries.insert(key.clone(), entry.clone());
+            }
+        }
+    }
+
+    /// the highest version that has been observed by this map
+    pub fn max_version(&self) -> Option<Version>{
+        self.entries.values()
+            .flat_map(|entry| entry.adds.keys().chain(entry.removes.iter()))
+            .max()
+            .copied()
+    }
+
+}
+
+
+/*
+
+    the crdt mode of the sharded map, every shard holds an or-map of lww
+    registers instead of a plain db, a whole replica can be exported with
+    state() and merged into any other replica with merge_state(), since the
+    merge is done per key the replicas don't need to have the same number of
+    shards and they will always converge including the deletes.
+
+*/
+pub struct CrdtMap<K: Eq + Hash, V, S = DefaultHashBuilder>{
+    shards: Vec<Arc<Mutex<OrMap<K, V>>>>,
+    hasher: S,
+    clock: HybridClock,
+}
+
+impl<K, V> CrdtMap<K, V> where
+    K: Eq + Hash + Clone,
+    V: Clone
+{
+
+    /// builds a replica with the given node id, every replica must have its own node id
+    pub fn new(shard_count: usize, node: u32) -> Self{
+        Self::with_hasher(shard_count, node, DefaultHashBuilder::default())
+    }
+
+}
+
+impl<K, V, S> CrdtMap<K, V, S> where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    S: BuildHasher
+{
+
+    pub fn with_hasher(shard_count: usize, node: u32, hasher: S) -> Self{
+        assert!(shard_count > 0, "a sharded map needs at least one shard");
+        let shards = (0..shard_count)
+            .map(|_| Arc::new(Mutex::new(OrMap::new())))
+            .collect();
+        Self{shards, hasher, clock: HybridClock::new(node)}
+    }
+
+    pub fn shard_count(&self) -> usize{
+        self.shards.len()
+    }
+
+    pub fn node(&self) -> u32{
+        self.clock.node()
+    }
+
+    pub fn shard_index(&self, key: &K) -> usize{
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard_for(&self, key: &K) -> &Arc<Mutex<OrMap<K, V>>>{
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub async fn get(&self, key: &K) -> Result<V>{
+        self.shard_for(key).lock().await
+            .get(key)
+            .cloned()
+            .ok_or(S3Error::KeyNotFound)
+    }
+
+    pub async fn get_versioned(&self, key: &K) -> Result<Versioned<V>>{
+        self.shard_for(key).lock().await
+            .get_register(key)
+            .map(|register| Versioned::new(register.value().clone(), register.version()))
+            .ok_or(S3Error::KeyNotFound)
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Result<Version>{
+        let mut gaurd = self.shard_for(&key).lock().await;
+        let version = self.clock.now();
+        gaurd.insert(key, value, version);
+        Ok(version)
+    }
+
+    pub async fn remove(&self, key: &K) -> Result<V>{
+        self.shard_for(key).lock().await
+            .remove(key)
+            .ok_or(S3Error::KeyNotFound)
+    }
+
+    pub async fn len(&self) -> Result<usize>{
+        let mut len = 0;
+        for shard in &self.shards{
+            len += shard.lock().await.len();
+        }
+        Ok(len)
+    }
+
+    pub async fn is_empty(&self) -> Result<bool>{
+        Ok(self.len().await? == 0)
+    }
+
+    /// the whole state of this replica as a single or-map, tombstones included
+    pub async fn state(&self) -> Result<OrMap<K, V>>{
+        let mut state = OrMap::new();
+        for shard in &self.shards{
+            state.merge(&*shard.lock().await);
+        }
+        Ok(state)
+    }
+
+    /// merges the state of another replica into this one
+    pub async fn merge_state(&self, remote: &OrMap<K, V>) -> Result<()>{
+        let mut parts = vec![OrMap::new(); self.shards.len()];
+        for (key, entry) in &remote.entries{
+            parts[self.shard_index(key)].merge_entry(key, entry);
+        }
+        for (shard, part) in self.shards.iter().zip(parts){
+            shard.lock().await.merge(&part);
+        }
+        if let Some(version) = remote.max_version(){
+            self.clock.observe(version);
+        }
+        Ok(())
+    }
+
+    pub async fn merge(&self, other: &Self) -> Result<()>{
+        self.merge_state(&other.state().await?).await
+    }
+
+}
@@ -0,0 +1,76 @@
+
+
+
+use std::fmt;
+use tokio::sync::{broadcast, mpsc};
+
+
+pub type Result<T, E = S3Error> = std::result::Result<T, E>;
+
+
+/// everything that can go wrong while working with a sharded map
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum S3Error{
+    /// the other side of a channel is gone, the map has been shut down
+    ChannelClosed,
+    /// a thread has panicked while it was holding the lock of the shard
+    ShardPoisoned{shard: usize},
+    /// a subscriber has fallen behind and missed this number of published updates
+    Lagged(u64),
+    /// the operation didn't finish in time
+    Timeout,
+    KeyNotFound,
+    /// the reconciler of the map has already been taken by another task
+    ReconcilerAlreadySpawned,
+    /// the keys of a group don't share the same home shard
+    CrossShard,
+    /// a group of keys has been locked without any key
+    EmptyGroup,
+    /// the pool can't be resharded or split like that, the reason is given
+    InvalidReshard(String),
+}
+
+impl fmt::Display for S3Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            S3Error::ChannelClosed => write!(f, "channel is closed"),
+            S3Error::ShardPoisoned{shard} => write!(f, "shard {} is poisoned", shard),
+            S3Error::Lagged(missed) => write!(f, "subscriber lagged behind by {} updates", missed),
+            S3Error::Timeout => write!(f, "operation timed out"),
+            S3Error::KeyNotFound => write!(f, "key not found"),
+            S3Error::ReconcilerAlreadySpawned => write!(f, "reconciler has already been spawned"),
+            S3Error::CrossShard => write!(f, "keys belong to different shards"),
+            S3Error::EmptyGroup => write!(f, "group has no keys"),
+            S3Error::InvalidReshard(reason) => write!(f, "invalid resharding: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for S3Error{}
+
+impl<T> From<mpsc::error::SendError<T>> for S3Error{
+    fn from(_: mpsc::error::SendError<T>) -> Self{
+        S3Error::ChannelClosed
+    }
+}
+
+impl<T> From<broadcast::error::SendError<T>> for S3Error{
+    fn from(_: broadcast::error::SendError<T>) -> Self{
+        S3Error::ChannelClosed
+    }
+}
+
+impl From<broadcast::error::RecvError> for S3Error{
+    fn from(e: broadcast::error::RecvError) -> Self{
+        match e{
+            broadcast::error::RecvError::Closed => S3Error::ChannelClosed,
+            broadcast::error::RecvError::Lagged(missed) => S3Error::Lagged(missed),
+        }
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for S3Error{
+    fn from(_: tokio::time::error::Elapsed) -> Self{
+        S3Error::Timeout
+    }
+}
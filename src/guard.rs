@@ -0,0 +1,187 @@
+
+
+
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::error::{Result, S3Error};
+use crate::lock::{LockBackend, ShardLock};
+use crate::map::{Db, ShardedMap, TimedWriteGuard};
+use crate::stats::{Op, Timed};
+use crate::refresh::WritePermit;
+use crate::version::{Version, Versioned};
+
+
+/*
+
+    the lock of a single shard that has been acquired by acquire_any_shard(),
+    it knows the index of its shard and tells the reconciler about the shard
+    once it gets dropped if it has been mutated, the keys that are put in here
+    may not belong to this shard, they will be moved to their home shard on
+    the next reconciliation, the inserts and the removes of the guard are
+    counted in the stats of its shard like the ones of the map, the writes
+    that go straight to the db through deref_mut() are not.
+
+*/
+pub struct ShardGuard<'a, K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+    map: &'a ShardedMap<K, V, S, B>,
+    index: usize,
+    gaurd: TimedWriteGuard<'a, K, V, B>,
+    mutated: bool,
+    _permit: WritePermit<'a>,
+}
+
+impl<'a, K, V, S, B> ShardGuard<'a, K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    pub(crate) fn new(map: &'a ShardedMap<K, V, S, B>, index: usize, gaurd: TimedWriteGuard<'a, K, V, B>, permit: WritePermit<'a>) -> Self{
+        Self{map, index, gaurd, mutated: false, _permit: permit}
+    }
+
+    /// index of the shard that is locked by this guard
+    pub fn index(&self) -> usize{
+        self.index
+    }
+
+    /// inserts the value with a new version of the map clock
+    pub fn insert(&mut self, key: K, value: V) -> (Option<V>, Version){
+        let version = self.map.clock().now();
+        let old = self.insert_versioned(key, Versioned::new(value, version));
+        (old.map(|versioned| versioned.value), version)
+    }
+
+    pub fn insert_versioned(&mut self, key: K, value: Versioned<V>) -> Option<Versioned<V>>{
+        self.mutated = true;
+        self.map.lock_stats(self.index).record_op(Op::Insert);
+        self.gaurd.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<Versioned<V>>{
+        self.mutated = true;
+        self.map.lock_stats(self.index).record_op(Op::Remove);
+        self.gaurd.remove(key)
+    }
+
+}
+
+impl<K, V, S, B> Deref for ShardGuard<'_, K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+    type Target = Db<K, Versioned<V>>;
+
+    fn deref(&self) -> &Self::Target{
+        &self.gaurd
+    }
+}
+
+impl<K, V, S, B> DerefMut for ShardGuard<'_, K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+    fn deref_mut(&mut self) -> &mut Self::Target{
+        self.mutated = true;
+        &mut self.gaurd
+    }
+}
+
+impl<K, V, S, B> Drop for ShardGuard<'_, K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+    fn drop(&mut self){
+        if self.mutated{
+            self.map.publish_view(self.index, &self.gaurd);
+            self.map.sync_replicas(&self.gaurd, |key| self.map.shard_index(key) == self.index);
+            let _ = self.map.notify(self.index);
+        }
+    }
+}
+
+
+/// keeps the number of tasks that are waiting on a shard up to date even if the wait gets cancelled
+pub(crate) struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a>{
+    pub(crate) fn new(waiters: &'a AtomicUsize) -> Self{
+        waiters.fetch_add(1, Ordering::Relaxed);
+        Self(waiters)
+    }
+}
+
+impl Drop for Waiting<'_>{
+    fn drop(&mut self){
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /*
+
+        finds a shard that is not being locked by other threads so the caller
+        doesn't have to wait for a locked shard to gets freed, all the shards
+        are tried once with try_lock() starting from a rotating cursor so the
+        callers get spread over the pool, if all of them are busy we'll wait
+        on the shard with the least number of waiters until the timeout, the
+        guard is also a writer of the pool so it has to wait for the refresh
+        phase to be finished.
+
+    */
+    #[tracing::instrument(level = "trace", skip_all, fields(shard))]
+    pub async fn acquire_any_shard(&self, timeout: Duration) -> Result<ShardGuard<'_, K, V, S, B>>{
+        let deadline = Instant::now() + timeout;
+        let permit = tokio::time::timeout_at(deadline, self.write_permit())
+            .await
+            .map_err(|_| S3Error::Timeout)??;
+
+        let shard_count = self.active_shards();
+        let start = self.next_cursor() % shard_count;
+        for offset in 0..shard_count{
+            let index = (start + offset) % shard_count;
+            let gaurd = self.shard(index).try_write().map_err(|_| S3Error::ShardPoisoned{shard: index})?;
+            if let Some(gaurd) = gaurd{
+                tracing::Span::current().record("shard", index);
+                self.lock_stats(index).record(false, Duration::ZERO);
+                return Ok(ShardGuard::new(self, index, Timed::new(gaurd, self.lock_stats(index)), permit));
+            }
+            //// this one is locked, use other shard instead
+            self.lock_stats(index).record_skip();
+        }
+
+        let index = (0..shard_count)
+            .map(|offset| (start + offset) % shard_count)
+            .min_by_key(|&index| self.waiters(index).load(Ordering::Relaxed))
+            .unwrap_or(start);
+        let gaurd = tokio::time::timeout_at(deadline, self.lock_shard(index))
+            .await
+            .map_err(|_| S3Error::Timeout)??;
+        tracing::Span::current().record("shard", index);
+        tracing::trace!("every shard was busy, waited for the least awaited one");
+        Ok(ShardGuard::new(self, index, gaurd, permit))
+    }
+
+}
@@ -0,0 +1,293 @@
+
+
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use arc_swap::ArcSwap;
+use crate::error::Result;
+use crate::lock::LockBackend;
+use crate::map::{ShardDb, ShardedMap};
+use crate::placement::mix;
+use crate::reshard::ReshardReport;
+use crate::version::Versioned;
+
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 1024;
+/// the counters of the sketch get halved after this many accesses so old heat fades away
+const SKETCH_WINDOW: u64 = 1 << 16;
+/// a shard is not split before someone has tried to lock it this many times
+const MIN_SPLIT_SAMPLES: u64 = 1024;
+
+
+/*
+
+    a count-min sketch of the key accesses, every key bumps one counter per
+    row and its estimated count is the smallest of its counters, so it never
+    underestimates a key and the popular keys stand out without keeping a
+    counter per key.
+
+*/
+struct Sketch{
+    counters: Vec<AtomicU32>,
+}
+
+impl Sketch{
+
+    fn new() -> Self{
+        Self{counters: (0..SKETCH_DEPTH * SKETCH_WIDTH).map(|_| AtomicU32::new(0)).collect()}
+    }
+
+    fn cells(&self, hash: u64) -> impl Iterator<Item = &AtomicU32>{
+        (0..SKETCH_DEPTH).map(move |row| {
+            let column = (mix(hash ^ mix(row as u64 + 1)) % SKETCH_WIDTH as u64) as usize;
+            &self.counters[row * SKETCH_WIDTH + column]
+        })
+    }
+
+    /// counts an access to the key and returns its new estimated count
+    fn increment(&self, hash: u64) -> u64{
+        self.cells(hash)
+            .map(|cell| cell.fetch_add(1, Ordering::Relaxed) as u64 + 1)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn estimate(&self, hash: u64) -> u64{
+        self.cells(hash)
+            .map(|cell| cell.load(Ordering::Relaxed) as u64)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// it's fine to lose a few increments that race with the halving
+    fn halve(&self){
+        for cell in &self.counters{
+            cell.store(cell.load(Ordering::Relaxed) / 2, Ordering::Relaxed);
+        }
+    }
+
+}
+
+
+/*
+
+    the hottest keys of the map, the candidates are kept in a small list
+    that can be checked without locking so a key that is already hot doesn't
+    serialize its accessors, the list is only locked when a key gets hotter
+    than the coldest candidate and takes its place.
+
+*/
+pub(crate) struct HotKeys<K>{
+    sketch: Sketch,
+    capacity: usize,
+    top: ArcSwap<Vec<(u64, K)>>,
+    threshold: AtomicU64,
+    accesses: AtomicU64,
+    updating: Mutex<()>,
+}
+
+impl<K: Eq + Clone> HotKeys<K>{
+
+    pub(crate) fn new(capacity: usize) -> Self{
+        Self{
+            sketch: Sketch::new(),
+            capacity,
+            top: ArcSwap::from_pointee(Vec::new()),
+            threshold: AtomicU64::new(0),
+            accesses: AtomicU64::new(0),
+            updating: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn record(&self, hash: u64, key: &K){
+        if (self.accesses.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(SKETCH_WINDOW){
+            self.sketch.halve();
+            self.threshold.store(self.threshold.load(Ordering::Relaxed) / 2, Ordering::Relaxed);
+        }
+        let count = self.sketch.increment(hash);
+        if count < self.threshold.load(Ordering::Relaxed)
+            || self.top.load().iter().any(|(h, k)| *h == hash && k == key){
+            return;
+        }
+
+        let _updating = self.updating.lock().unwrap_or_else(|e| e.into_inner());
+        let mut top = self.top.load().to_vec();
+        if top.iter().any(|(h, k)| *h == hash && k == key){
+            return;
+        }
+        top.push((hash, key.clone()));
+        if top.len() > self.capacity{
+            let coldest = top.iter()
+                .enumerate()
+                .min_by_key(|(_, (h, _))| self.sketch.estimate(*h))
+                .map(|(idx, _)| idx)
+                .unwrap_or(0);
+            top.swap_remove(coldest);
+        }
+        if top.len() == self.capacity{
+            let threshold = top.iter().map(|(h, _)| self.sketch.estimate(*h)).min().unwrap_or(0);
+            self.threshold.store(threshold, Ordering::Relaxed);
+        }
+        self.top.store(top.into());
+    }
+
+    /// the hot keys along with their estimated number of accesses, the hottest first
+    pub(crate) fn hottest(&self) -> Vec<(K, u64)>{
+        let mut hottest = self.top.load()
+            .iter()
+            .map(|(hash, key)| (key.clone(), self.sketch.estimate(*hash)))
+            .collect::<Vec<_>>();
+        hottest.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        hottest
+    }
+
+}
+
+
+/// how busy the lock of a shard is
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShardHeat{
+    pub shard: usize,
+    /// number of times the shard has been write locked
+    pub acquisitions: u64,
+    /// number of times someone has tried to lock the shard, the skipped ones included
+    pub attempts: u64,
+    /// number of times the shard was already locked by someone else
+    pub contended: u64,
+}
+
+impl ShardHeat{
+
+    /// the part of the attempts that had to wait or skip the shard
+    pub fn contention(&self) -> f64{
+        if self.attempts == 0{
+            return 0.0;
+        }
+        self.contended as f64 / self.attempts as f64
+    }
+
+}
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /// the hottest keys of the map with their estimated accesses, empty if they're not tracked
+    pub fn hot_keys(&self) -> Vec<(K, u64)>{
+        self.hot().map(HotKeys::hottest).unwrap_or_default()
+    }
+
+    /// the shards of the pool, the most contended first
+    pub fn hot_shards(&self) -> Vec<ShardHeat>{
+        let mut shards = (0..self.shard_count())
+            .map(|shard| {
+                let stats = self.lock_stats(shard);
+                ShardHeat{shard, acquisitions: stats.acquisitions(), attempts: stats.attempts(), contended: stats.contended()}
+            })
+            .collect::<Vec<_>>();
+        shards.sort_by_key(|heat| std::cmp::Reverse(heat.contended));
+        shards
+    }
+
+    pub(crate) fn record_access(&self, key: &K){
+        if let Some(hot) = self.hot(){
+            hot.record(self.key_hash(key), key);
+        }
+    }
+
+    /*
+
+        splits the most contended shard into `parts` sub shards if more than
+        `contention` of its locks had to wait, the lock counters of the split
+        shard start over so it won't be split again right away, returns None
+        if no shard is that hot.
+
+    */
+    pub async fn split_hot_shard(&self, contention: f64, parts: usize) -> Result<Option<ReshardReport>>{
+        let hottest = self.hot_shards()
+            .into_iter()
+            .filter(|heat| heat.attempts >= MIN_SPLIT_SAMPLES)
+            .max_by(|a, b| a.contention().total_cmp(&b.contention()));
+        match hottest{
+            Some(heat) if heat.contention() > contention => {
+                tracing::info!(shard = heat.shard, contention = heat.contention(), parts, "splitting the hot shard");
+                let report = self.split_shard(heat.shard, parts).await?;
+                self.lock_stats(heat.shard).reset_contention();
+                Ok(Some(report))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /*
+
+        replicates the `count` hottest keys so reading them doesn't lock
+        their shard anymore, a replica is updated by every write to its key
+        while the key's shard is still locked, so it's never behind the shard,
+        the keys that were replicated before are dropped from the replicas.
+
+    */
+    pub async fn replicate_hot_keys(&self, count: usize) -> Result<usize>{
+        self.clear_replicas();
+        let mut replicated = 0;
+        for (key, _) in self.hot_keys().into_iter().take(count){
+            let (_, gaurd) = self.lock_home(&key).await?;
+            if let Some(value) = gaurd.get(&key){
+                self.replicas().rcu(|replicas| {
+                    let mut replicas = HashMap::clone(replicas);
+                    replicas.insert(key.clone(), value.clone());
+                    replicas
+                });
+                replicated += 1;
+            }
+        }
+        Ok(replicated)
+    }
+
+    pub fn clear_replicas(&self){
+        self.replicas().store(Default::default());
+    }
+
+    /// updates the replica of the key if it's replicated, the caller must hold the lock of its home
+    pub(crate) fn update_replica(&self, key: &K, value: Option<&Versioned<V>>){
+        if !self.replicas().load().contains_key(key){
+            return;
+        }
+        self.replicas().rcu(|replicas| {
+            let mut replicas = HashMap::clone(replicas);
+            match value{
+                Some(value) => replicas.insert(key.clone(), value.clone()),
+                None => replicas.remove(key),
+            };
+            replicas
+        });
+    }
+
+    /// the replica of a hot key, it's read without any lock
+    pub(crate) fn replica(&self, key: &K) -> Option<Versioned<V>>{
+        self.replicas().load().get(key).cloned()
+    }
+
+    /// updates the replicas of the keys that are owned by the db, the caller must hold the lock of the db
+    pub(crate) fn sync_replicas(&self, db: &ShardDb<K, V>, owns: impl Fn(&K) -> bool){
+        let replicas = self.replicas().load();
+        if replicas.is_empty() || !replicas.keys().any(&owns){
+            return;
+        }
+        self.replicas().rcu(|replicas| {
+            replicas.iter()
+                .filter_map(|(key, replica)| match owns(key){
+                    true => db.get(key).map(|value| (key.clone(), value.clone())),
+                    false => Some((key.clone(), replica.clone())),
+                })
+                .collect::<HashMap<_, _>>()
+        });
+    }
+
+}
@@ -0,0 +1,59 @@
+
+
+
+//! 🇸3️ (Sharded Shared State)
+//!
+//! a very simple sharded shared state design pattern using standard `HashMap`
+//! as the shared data, the map is splitted into a pool of tokio mutexed shards
+//! so threads can work on different shards instead of waiting on a single lock,
+//! every key is routed to its home shard by hashing it with a pluggable `BuildHasher`
+//! which is FxHash by default, values are versioned with a hybrid logical clock
+//! so conflicting copies of a key are resolved by the last writer, there is also
+//! a crdt mode (`CrdtMap`) where every shard is an or-map of lww registers so
+//! replicas can be merged in any order and always converge.
+
+
+pub mod affinity;
+pub mod crdt;
+pub mod error;
+pub mod guard;
+pub mod heat;
+pub mod lifecycle;
+pub mod lock;
+pub mod map;
+pub mod metrics;
+pub mod placement;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod reconcile;
+pub mod reconciler;
+pub mod reshard;
+pub mod refresh;
+pub mod sim;
+pub mod stats;
+pub mod subscriber;
+mod sync;
+pub mod version;
+pub mod view;
+
+pub use affinity::{hash_tag, ByGroup, ByHashTag, ByKey, Routing};
+pub use crdt::{CrdtMap, LwwRegister, OrMap};
+pub use error::{Result, S3Error};
+pub use guard::ShardGuard;
+pub use heat::ShardHeat;
+pub use lifecycle::{ShutdownReport, TaskPanic};
+pub use lock::{BlockingBackend, LockBackend, Poisoned, ShardLock, StdMutex, StdRwLock, TokioMutex, TokioRwLock};
+#[cfg(feature = "parking_lot")]
+pub use lock::{ParkingLotMutex, ParkingLotRwLock};
+#[cfg(s3_loom)]
+pub use lock::LoomMutex;
+pub use map::{Db, DefaultHashBuilder, Published, Shard, ShardedMap, ShardedMapBuilder, Shards};
+pub use metrics::Metrics;
+pub use placement::Placement;
+pub use reconciler::Reconciler;
+pub use reshard::ReshardReport;
+pub use refresh::WritePermit;
+pub use stats::{ShardStats, Stats};
+pub use subscriber::Subscriber;
+pub use tokio_util::sync::CancellationToken;
+pub use version::{HybridClock, Version, Versioned};
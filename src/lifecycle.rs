@@ -0,0 +1,151 @@
+
+
+
+use std::any::Any;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use crate::error::Result;
+use crate::lock::LockBackend;
+use crate::map::ShardedMap;
+use crate::reconciler::Reconciler;
+
+
+/// a task of the map that has panicked instead of returning
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskPanic{
+    pub task: String,
+    pub message: String,
+}
+
+/// what happened to the background tasks of the map during the shutdown
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport{
+    /// number of tasks that have been awaited, the reconciler included
+    pub joined: usize,
+    pub panicked: Vec<TaskPanic>,
+}
+
+impl ShutdownReport{
+
+    pub fn is_clean(&self) -> bool{
+        self.panicked.is_empty()
+    }
+
+}
+
+
+/*
+
+    the background tasks of a map, the writers are cancelled and awaited
+    first and then the reconciler, so the reconciler can drain every update
+    that the writers have sent to the queue before they stopped.
+
+*/
+#[derive(Default)]
+pub(crate) struct Tasks{
+    writers_token: CancellationToken,
+    reconciler_token: CancellationToken,
+    writers: Mutex<Vec<(String, JoinHandle<()>)>>,
+    reconciler: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Tasks{
+
+    fn writers(&self) -> std::sync::MutexGuard<'_, Vec<(String, JoinHandle<()>)>>{
+        self.writers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn reconciler(&self) -> std::sync::MutexGuard<'_, Option<JoinHandle<()>>>{
+        self.reconciler.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+}
+
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+
+    /// spawns the background reconciler of the map, it runs until the map gets shut down
+    pub fn spawn_reconciler(self: &Arc<Self>) -> Result<()>{
+        let reconciler = Reconciler::new(self.clone())?;
+        let handle = reconciler.spawn(self.tasks().reconciler_token.clone());
+        *self.tasks().reconciler() = Some(handle);
+        Ok(())
+    }
+
+    /*
+
+        spawns a task that works on the map, the task gets a token that
+        will be cancelled once the map is being shut down so it can stop
+        what it's doing, the map awaits the task during the shutdown.
+
+    */
+    pub fn spawn_task<F, Fut>(&self, name: impl Into<String>, task: F) where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static
+    {
+        let handle = tokio::spawn(task(self.tasks().writers_token.clone()));
+        self.tasks().writers().push((name.into(), handle));
+    }
+
+    /// the token that gets cancelled once the map is being shut down
+    pub fn shutdown_token(&self) -> CancellationToken{
+        self.tasks().writers_token.clone()
+    }
+
+    /*
+
+        signals all the tasks of the map to stop, the writers are awaited
+        first then the reconciler drains the pending updates of the queue,
+        publishes the last merged data and exits, the panics of the tasks
+        are reported instead of being propagated.
+
+    */
+    #[tracing::instrument(level = "info", skip_all)]
+    pub async fn shutdown(&self) -> ShutdownReport{
+        let mut report = ShutdownReport::default();
+
+        self.tasks().writers_token.cancel();
+        let writers = std::mem::take(&mut *self.tasks().writers());
+        for (name, handle) in writers{
+            join(&mut report, name, handle).await;
+        }
+
+        self.tasks().reconciler_token.cancel();
+        let reconciler = self.tasks().reconciler().take();
+        if let Some(handle) = reconciler{
+            join(&mut report, "reconciler".to_string(), handle).await;
+        }
+
+        report
+    }
+
+}
+
+async fn join(report: &mut ShutdownReport, task: String, handle: JoinHandle<()>){
+    report.joined += 1;
+    if let Err(e) = handle.await{
+        if e.is_panic(){
+            let message = panic_message(e.into_panic());
+            tracing::error!(task, message, "the task has panicked");
+            report.panicked.push(TaskPanic{task, message});
+        }
+    }
+}
+
+/// the message a task has panicked with, if it has panicked with a string
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String{
+    match payload.downcast::<String>(){
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
@@ -0,0 +1,396 @@
+
+
+
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use crate::error::{Result, S3Error};
+
+
+/// the lock has been poisoned by a thread that panicked while holding it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Poisoned;
+
+
+/*
+
+    the lock that protects the db of a single shard, the shards can either
+    be locked none blocking using the tokio locks or blocking using the std
+    and parking_lot locks, the blocking ones are locked once their future
+    gets polled so the future itself never holds a guard that is not Send,
+    the rwlock ones let the readers of a shard share the lock.
+
+*/
+pub trait ShardLock<T>: Send + Sync + Sized{
+    type ReadGuard<'a>: Deref<Target = T> where Self: 'a;
+    type WriteGuard<'a>: DerefMut<Target = T> where Self: 'a;
+
+    fn new(value: T) -> Self;
+
+    fn read(&self) -> impl Future<Output = Result<Self::ReadGuard<'_>, Poisoned>> + Send;
+
+    fn write(&self) -> impl Future<Output = Result<Self::WriteGuard<'_>, Poisoned>> + Send;
+
+    /// None if the lock is being held by someone else
+    fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>;
+
+    /// write locks all the given locks in order and holds them together
+    fn write_all<'a>(locks: &[&'a Self]) -> impl Future<Output = Result<Vec<Self::WriteGuard<'a>>>> + Send;
+}
+
+/// a family of shard locks, this is what gets picked as the lock backend of a map
+pub trait LockBackend: Send + Sync + 'static{
+    type Lock<T: Send + Sync>: ShardLock<T>;
+
+    /// lets the holder of a busy lock go on before the lock is tried again
+    fn backoff() -> impl Future<Output = ()> + Send{
+        tokio::task::yield_now()
+    }
+}
+
+/// a backend whose locks block the thread instead of yielding, so the map can be used without a runtime
+pub trait BlockingBackend: LockBackend{}
+
+
+/// tokio::sync::Mutex, the default backend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioMutex;
+
+/// tokio::sync::RwLock, readers of a shard share the lock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioRwLock;
+
+/// std::sync::Mutex
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdMutex;
+
+/// std::sync::RwLock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdRwLock;
+
+impl LockBackend for TokioMutex{
+    type Lock<T: Send + Sync> = tokio::sync::Mutex<T>;
+}
+
+impl LockBackend for TokioRwLock{
+    type Lock<T: Send + Sync> = tokio::sync::RwLock<T>;
+}
+
+impl LockBackend for StdMutex{
+    type Lock<T: Send + Sync> = std::sync::Mutex<T>;
+}
+
+impl LockBackend for StdRwLock{
+    type Lock<T: Send + Sync> = std::sync::RwLock<T>;
+}
+
+impl BlockingBackend for StdMutex{}
+impl BlockingBackend for StdRwLock{}
+
+
+impl<T: Send + Sync> ShardLock<T> for tokio::sync::Mutex<T>{
+    type ReadGuard<'a> = tokio::sync::MutexGuard<'a, T> where T: 'a;
+    type WriteGuard<'a> = tokio::sync::MutexGuard<'a, T> where T: 'a;
+
+    fn new(value: T) -> Self{
+        tokio::sync::Mutex::new(value)
+    }
+
+    async fn read(&self) -> Result<Self::ReadGuard<'_>, Poisoned>{
+        Ok(self.lock().await)
+    }
+
+    async fn write(&self) -> Result<Self::WriteGuard<'_>, Poisoned>{
+        Ok(self.lock().await)
+    }
+
+    fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+        Ok(self.try_lock().ok())
+    }
+
+    async fn write_all<'a>(locks: &[&'a Self]) -> Result<Vec<Self::WriteGuard<'a>>>{
+        let mut gaurds = Vec::with_capacity(locks.len());
+        for &lock in locks{
+            gaurds.push(lock.lock().await);
+        }
+        Ok(gaurds)
+    }
+}
+
+impl<T: Send + Sync> ShardLock<T> for tokio::sync::RwLock<T>{
+    type ReadGuard<'a> = tokio::sync::RwLockReadGuard<'a, T> where T: 'a;
+    type WriteGuard<'a> = tokio::sync::RwLockWriteGuard<'a, T> where T: 'a;
+
+    fn new(value: T) -> Self{
+        tokio::sync::RwLock::new(value)
+    }
+
+    async fn read(&self) -> Result<Self::ReadGuard<'_>, Poisoned>{
+        Ok(tokio::sync::RwLock::read(self).await)
+    }
+
+    async fn write(&self) -> Result<Self::WriteGuard<'_>, Poisoned>{
+        Ok(tokio::sync::RwLock::write(self).await)
+    }
+
+    fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+        Ok(tokio::sync::RwLock::try_write(self).ok())
+    }
+
+    async fn write_all<'a>(locks: &[&'a Self]) -> Result<Vec<Self::WriteGuard<'a>>>{
+        let mut gaurds = Vec::with_capacity(locks.len());
+        for &lock in locks{
+            gaurds.push(tokio::sync::RwLock::write(lock).await);
+        }
+        Ok(gaurds)
+    }
+}
+
+impl<T: Send + Sync> ShardLock<T> for std::sync::Mutex<T>{
+    type ReadGuard<'a> = std::sync::MutexGuard<'a, T> where T: 'a;
+    type WriteGuard<'a> = std::sync::MutexGuard<'a, T> where T: 'a;
+
+    fn new(value: T) -> Self{
+        std::sync::Mutex::new(value)
+    }
+
+    fn read(&self) -> impl Future<Output = Result<Self::ReadGuard<'_>, Poisoned>> + Send{
+        locking(move || self.lock().map_err(|_| Poisoned))
+    }
+
+    fn write(&self) -> impl Future<Output = Result<Self::WriteGuard<'_>, Poisoned>> + Send{
+        locking(move || self.lock().map_err(|_| Poisoned))
+    }
+
+    fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+        match self.try_lock(){
+            Ok(gaurd) => Ok(Some(gaurd)),
+            Err(std::sync::TryLockError::WouldBlock) => Ok(None),
+            Err(std::sync::TryLockError::Poisoned(_)) => Err(Poisoned),
+        }
+    }
+
+    fn write_all<'a>(locks: &[&'a Self]) -> impl Future<Output = Result<Vec<Self::WriteGuard<'a>>>> + Send{
+        locking(move || lock_all(locks, |lock| lock.lock().map_err(|_| Poisoned)))
+    }
+}
+
+impl<T: Send + Sync> ShardLock<T> for std::sync::RwLock<T>{
+    type ReadGuard<'a> = std::sync::RwLockReadGuard<'a, T> where T: 'a;
+    type WriteGuard<'a> = std::sync::RwLockWriteGuard<'a, T> where T: 'a;
+
+    fn new(value: T) -> Self{
+        std::sync::RwLock::new(value)
+    }
+
+    fn read(&self) -> impl Future<Output = Result<Self::ReadGuard<'_>, Poisoned>> + Send{
+        locking(move || std::sync::RwLock::read(self).map_err(|_| Poisoned))
+    }
+
+    fn write(&self) -> impl Future<Output = Result<Self::WriteGuard<'_>, Poisoned>> + Send{
+        locking(move || std::sync::RwLock::write(self).map_err(|_| Poisoned))
+    }
+
+    fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+        match std::sync::RwLock::try_write(self){
+            Ok(gaurd) => Ok(Some(gaurd)),
+            Err(std::sync::TryLockError::WouldBlock) => Ok(None),
+            Err(std::sync::TryLockError::Poisoned(_)) => Err(Poisoned),
+        }
+    }
+
+    fn write_all<'a>(locks: &[&'a Self]) -> impl Future<Output = Result<Vec<Self::WriteGuard<'a>>>> + Send{
+        locking(move || lock_all(locks, |lock| std::sync::RwLock::write(lock).map_err(|_| Poisoned)))
+    }
+}
+
+
+#[cfg(feature = "parking_lot")]
+pub use self::parking::{ParkingLotMutex, ParkingLotRwLock};
+
+#[cfg(feature = "parking_lot")]
+mod parking{
+
+    use std::future::Future;
+    use super::{lock_all, locking, BlockingBackend, LockBackend, Poisoned, ShardLock};
+    use crate::error::Result;
+
+    /// parking_lot::Mutex, it never gets poisoned
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct ParkingLotMutex;
+
+    /// parking_lot::RwLock, it never gets poisoned
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct ParkingLotRwLock;
+
+    impl LockBackend for ParkingLotMutex{
+        type Lock<T: Send + Sync> = parking_lot::Mutex<T>;
+    }
+
+    impl LockBackend for ParkingLotRwLock{
+        type Lock<T: Send + Sync> = parking_lot::RwLock<T>;
+    }
+
+    impl BlockingBackend for ParkingLotMutex{}
+    impl BlockingBackend for ParkingLotRwLock{}
+
+    impl<T: Send + Sync> ShardLock<T> for parking_lot::Mutex<T>{
+        type ReadGuard<'a> = parking_lot::MutexGuard<'a, T> where T: 'a;
+        type WriteGuard<'a> = parking_lot::MutexGuard<'a, T> where T: 'a;
+
+        fn new(value: T) -> Self{
+            parking_lot::Mutex::new(value)
+        }
+
+        fn read(&self) -> impl Future<Output = Result<Self::ReadGuard<'_>, Poisoned>> + Send{
+            locking(move || Ok(self.lock()))
+        }
+
+        fn write(&self) -> impl Future<Output = Result<Self::WriteGuard<'_>, Poisoned>> + Send{
+            locking(move || Ok(self.lock()))
+        }
+
+        fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+            Ok(self.try_lock())
+        }
+
+        fn write_all<'a>(locks: &[&'a Self]) -> impl Future<Output = Result<Vec<Self::WriteGuard<'a>>>> + Send{
+            locking(move || lock_all(locks, |lock| Ok(lock.lock())))
+        }
+    }
+
+    impl<T: Send + Sync> ShardLock<T> for parking_lot::RwLock<T>{
+        type ReadGuard<'a> = parking_lot::RwLockReadGuard<'a, T> where T: 'a;
+        type WriteGuard<'a> = parking_lot::RwLockWriteGuard<'a, T> where T: 'a;
+
+        fn new(value: T) -> Self{
+            parking_lot::RwLock::new(value)
+        }
+
+        fn read(&self) -> impl Future<Output = Result<Self::ReadGuard<'_>, Poisoned>> + Send{
+            locking(move || Ok(parking_lot::RwLock::read(self)))
+        }
+
+        fn write(&self) -> impl Future<Output = Result<Self::WriteGuard<'_>, Poisoned>> + Send{
+            locking(move || Ok(parking_lot::RwLock::write(self)))
+        }
+
+        fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+            Ok(parking_lot::RwLock::try_write(self))
+        }
+
+        fn write_all<'a>(locks: &[&'a Self]) -> impl Future<Output = Result<Vec<Self::WriteGuard<'a>>>> + Send{
+            locking(move || lock_all(locks, |lock| Ok(parking_lot::RwLock::write(lock))))
+        }
+    }
+
+}
+
+
+#[cfg(s3_loom)]
+pub use self::model::LoomMutex;
+
+#[cfg(s3_loom)]
+mod model{
+
+    use std::future::Future;
+    use super::{lock_all, locking, LockBackend, Poisoned, ShardLock};
+    use crate::error::Result;
+
+    /*
+
+        loom::sync::Mutex, only there so the loom models in tests/loom.rs can
+        run the map itself, loom only knows about the threads and locks of its
+        own so a busy lock has to be backed off through loom, it's not a
+        blocking backend since loom threads can't be parked by our block_on.
+
+    */
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct LoomMutex;
+
+    impl LockBackend for LoomMutex{
+        type Lock<T: Send + Sync> = loom::sync::Mutex<T>;
+
+        async fn backoff(){
+            loom::thread::yield_now();
+        }
+    }
+
+    impl<T: Send + Sync> ShardLock<T> for loom::sync::Mutex<T>{
+        type ReadGuard<'a> = loom::sync::MutexGuard<'a, T> where T: 'a;
+        type WriteGuard<'a> = loom::sync::MutexGuard<'a, T> where T: 'a;
+
+        fn new(value: T) -> Self{
+            loom::sync::Mutex::new(value)
+        }
+
+        fn read(&self) -> impl Future<Output = Result<Self::ReadGuard<'_>, Poisoned>> + Send{
+            locking(move || self.lock().map_err(|_| Poisoned))
+        }
+
+        fn write(&self) -> impl Future<Output = Result<Self::WriteGuard<'_>, Poisoned>> + Send{
+            locking(move || self.lock().map_err(|_| Poisoned))
+        }
+
+        fn try_write(&self) -> Result<Option<Self::WriteGuard<'_>>, Poisoned>{
+            match self.try_lock(){
+                Ok(gaurd) => Ok(Some(gaurd)),
+                Err(std::sync::TryLockError::WouldBlock) => Ok(None),
+                Err(std::sync::TryLockError::Poisoned(_)) => Err(Poisoned),
+            }
+        }
+
+        fn write_all<'a>(locks: &[&'a Self]) -> impl Future<Output = Result<Vec<Self::WriteGuard<'a>>>> + Send{
+            locking(move || lock_all(locks, |lock| lock.lock().map_err(|_| Poisoned)))
+        }
+    }
+
+}
+
+
+/// locks a blocking lock once the future gets polled instead of when the future is made
+fn locking<G>(lock: impl FnOnce() -> G + Send) -> impl Future<Output = G> + Send{
+    let mut lock = Some(lock);
+    std::future::poll_fn(move |_| Poll::Ready(lock.take().expect("polled after completion")()))
+}
+
+/// locks the blocking locks one by one, the error tells which one has been poisoned
+fn lock_all<'a, L, G>(locks: &[&'a L], lock: impl Fn(&'a L) -> Result<G, Poisoned>) -> Result<Vec<G>>{
+    locks.iter()
+        .copied()
+        .enumerate()
+        .map(|(shard, l)| lock(l).map_err(|_| S3Error::ShardPoisoned{shard}))
+        .collect()
+}
+
+
+/*
+
+    drives a future to completion on the current thread, this is used by the
+    blocking api of the maps with a blocking backend, their locks are ready
+    right away and the only thing that may park the thread is the refresh
+    phase of the pool.
+
+*/
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output{
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker{
+        fn wake(self: Arc<Self>){
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop{
+        match future.as_mut().poll(&mut cx){
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -2,168 +2,243 @@
 
 
 
-use std::collections::HashMap;
+mod bench;
+mod config;
+mod simulate;
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::path::Path;
 use std::sync::Arc;
-use once_cell::sync::Lazy;
-use rand::{Rng, SeedableRng, RngCore};
-use rand_chacha::{rand_core, ChaCha12Rng};
+use std::time::Duration;
+use clap::{CommandFactory, Parser};
+use rand::Rng;
+use s3::{DefaultHashBuilder, LockBackend, ShardedMap, ShardedMapBuilder, StdMutex, StdRwLock, TokioMutex, TokioRwLock};
+use tracing_subscriber::EnvFilter;
+use config::{Backend, Cli, Command, Config, Hasher};
+
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// how long the demo waits for all of its writes to be published
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
 
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>{
-    
+async fn main() -> Result<(), BoxError>{
+
+    //// RUST_LOG=s3=trace shows every shard operation
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    let loaded = match &command{
+        Some(Command::Bench(args)) => args.validate().and_then(|_| Config::load(cli)),
+        Some(Command::Sim(args)) => Config::load(cli).and_then(|config| args.validate(&config).map(|_| config)),
+        None => Config::load(cli),
+    };
+    let config = match loaded{
+        Ok(config) => config,
+        Err(e) => Cli::command().error(clap::error::ErrorKind::ValueValidation, e).exit(),
+    };
+
+    match config.hasher{
+        Hasher::Fx => with_backend(&config, command, DefaultHashBuilder::default()).await,
+        Hasher::Sip => with_backend(&config, command, RandomState::new()).await,
+    }
+
+}
+
+/// the lock backend is a type parameter of the map so every backend gets its own run
+async fn with_backend<S>(config: &Config, command: Option<Command>, hasher: S) -> Result<(), BoxError> where
+    S: BuildHasher + Clone + Send + Sync + 'static
+{
+    let builder = config.builder().hasher(hasher);
+    match config.backend{
+        Backend::TokioMutex => start(config, command, builder.backend::<TokioMutex>()).await,
+        Backend::TokioRwlock => start(config, command, builder.backend::<TokioRwLock>()).await,
+        Backend::StdMutex => start(config, command, builder.backend::<StdMutex>()).await,
+        Backend::StdRwlock => start(config, command, builder.backend::<StdRwLock>()).await,
+        #[cfg(feature = "parking_lot")]
+        Backend::ParkingLotMutex => start(config, command, builder.backend::<s3::ParkingLotMutex>()).await,
+        #[cfg(feature = "parking_lot")]
+        Backend::ParkingLotRwlock => start(config, command, builder.backend::<s3::ParkingLotRwLock>()).await,
+        #[cfg(not(feature = "parking_lot"))]
+        Backend::ParkingLotMutex | Backend::ParkingLotRwlock => unreachable!("rejected by the config validation"),
+    }
+}
+
+async fn start<S, B>(config: &Config, command: Option<Command>, builder: ShardedMapBuilder<S, B>) -> Result<(), BoxError> where
+    S: BuildHasher + Clone + Send + Sync + 'static,
+    B: LockBackend + Clone
+{
+    match command{
+        None => run(config, builder.build()).await,
+        Some(Command::Bench(args)) => Ok(bench::run(&args, config.rng(), builder.build()).await?),
+        Some(Command::Sim(args)) => Ok(simulate::run(&args, config.seed.unwrap_or_else(rand::random), builder)?),
+    }
+}
+
+async fn run<S, B>(config: &Config, map: ShardedMap<i32, String, S, B>) -> Result<(), BoxError> where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+
     /*
 
-    
-        shared state sharding to decrease the time lock, we can use a shard from 
-        the pool to update the mutex by locking on it inside a thread (either blocking 
-        using std::sync::Mutex or none blocking using tokio::sync::Mutex) and if other 
-        thread wants to use it, it can use other shard instead of waiting for the locked 
-        shard to gets freed, we can use try_lock() method to check that the shard is 
-        currently being locked or not also we have to update the whole shards inside 
+
+        shared state sharding to decrease the time lock, we can use a shard from
+        the pool to update the mutex by locking on it inside a thread (either blocking
+        using std::sync::Mutex or none blocking using tokio::sync::Mutex) and if other
+        thread wants to use it, it can use other shard instead of waiting for the locked
+        shard to gets freed, we can use try_lock() method to check that the shard is
+        currently being locked or not also we have to update the whole shards inside
         the pool at the end of each mutex free process which is something that will
-        be taken care of by semaphores, also we've used tokio mutex to lock on the 
+        be taken care of by semaphores, also we've used tokio mutex to lock on the
         mutex asyncly instead of using std mutex which is a blocking manner.
-        
-        
-        struct Data{id: String}
-        let (sender, mut receiver) = tokio::sync::mpsc::channel::<Arc<tokio::sync::Mutex<Data>>>(1024);
-        let data_ = Arc::new(tokio::sync::Mutex::new(Data{id: "0".to_string()}));
-        tokio::spawn(async move{
-            sender.send(data_).await;
-        });
-        tokio::select!{
-            data = receiver.recv() => {
-                if let Some(d) = data{
-                    // we got data
-                    // ...
-                }
-            }
-        }
-    
-    
+
+        the pool itself lives inside the s3 lib crate as the ShardedMap type,
+        this binary is just a demo on top of it.
+
     */
 
+    let shards = config.shards;
+    let map = Arc::new(map);
+    if let Some(path) = &config.snapshot{
+        let loaded = load_snapshot(&map, path).await?;
+        println!("loaded {} entries from {}", loaded, path.display());
+    }
+    #[cfg(feature = "prometheus")]
+    if let Some(addr) = config.metrics_addr{
+        let addr = map.serve_metrics(addr).await?;
+        println!("serving the metrics at http://{}/metrics", addr);
+    }
+
+    let rand_generator = Arc::new(tokio::sync::Mutex::new(config.rng()));
 
 
-    pub const LAZY_STATIC_SHARED_DATA: Lazy<Db> = Lazy::new(||{
-        HashMap::new()
-    });
-    
-    type Db = HashMap<i32, String>;
-    let shards = 10;
-
-    let rand_generator = Arc::new(tokio::sync::Mutex::new(ChaCha12Rng::from_entropy()));
-
-    let (mutex_data_sender, mut mutex_data_receiver) = tokio::sync::mpsc::channel::<Db>(shards as usize);
-    let (map_shards_sender, mut map_shards_receiver) = tokio::sync::broadcast::channel::<Vec<Arc<tokio::sync::Mutex<Db>>>>(shards as usize);
-    
-    let send_sync_map = Arc::new(tokio::sync::Mutex::new(LAZY_STATIC_SHARED_DATA.clone())); //// no need to put in Mutex since we don't want to mutate it
-    let mutex_data_sender = mutex_data_sender.clone();
-    
     /*
-        
-        initializing the map shards so we can store all
-        the mutexed db instances in there and udpate it 
-        during the lock acquisition inside the app.
+
+        the reconciler runs in the background until the map gets shut
+        down, it takes every update of the map from the mpsc job queue,
+        merges the shards and publishes the merged data to all the
+        subscribers so we'll always use an udpated version of the shards
 
     */
-    let mut map_shards = vec![send_sync_map; shards];
-    let mut current_data_length = map_shards[0].lock().await.len();
+    map.spawn_reconciler()?;
+    let mut subscriber = map.subscribe();
 
 
     /*
 
-        waiting to receive the new shards from the channel 
-        to update the current shard inside the whole app
-        asyncly, since we're inside an eventloop this can 
-        be done at any time inside the app thus we're sure
-        that we'll always use an udpated version of the shards 
+        inserting into the map, each insert goes to the home shard of
+        its key which is found by hashing the key, the map itself sends
+        the index of the mutated shard to downside of the mpsc job queue
+        channel in order to reconcile the shards, the writer stops as soon
+        as the map is being shut down.
 
     */
-    tokio::select!{ //// instead of using while let ... syntax
-        sent_shards = map_shards_receiver.recv() => {
-            if let Ok(shards) = sent_shards{
-                map_shards = shards;
-                current_data_length = map_shards[0].lock().await.len();
+    let writer_map = map.clone();
+    map.spawn_task("writer", |token| async move{
+        let generator = rand_generator.clone();
+        for idx in 0..writer_map.shard_count(){
+            if token.is_cancelled(){
+                break;
             }
-        }
-    }
-
 
-    /*
+            // generate random number
+            let random = generator.lock().await.gen::<i32>();
 
-        after finding a free mutex we'll update it then send it to the 
-        downside of the mpsc job queue channel in order to update the vector 
-        of shards by selecting the largest mutex data, this must be done asyncly 
-        since we don't know the time of the lock acquisition, it can happen any 
-        time during the app and due to having a shard of mutex data we have to 
-        update the whole shards with the latest data in a none blocking manner
-        since there might be other mutex-es that are in a lock process.    
-    
-    */
-    tokio::spawn(async move{
-        let generator = rand_generator.clone(); 
-        for idx in 0..map_shards.clone().len(){
-            match map_shards[idx].clone().try_lock(){
-                Ok(mut gaurd) => {
+            // udpate the map
+            let value = format!("value is {}", idx);
+            if let Err(e) = writer_map.insert((idx as i32).wrapping_mul(random), value).await{
+                tracing::warn!(error = %e, "writer stopped");
+                break;
+            }
 
-                    // generate random number
-                    let mut rng = generator.lock().await;
-                    let random = rng.to_owned().gen::<i32>();
+        }
+    });
 
-                    // udpate the gaurd 
-                    let value = format!("value is {}", idx);
-                    gaurd.insert((idx as i32) * random, value);
+    /*
 
-                    // send the mutex to downside of the channel
-                    mutex_data_sender.send(gaurd.to_owned()).await.unwrap();
+        this one doesn't care about the home of its keys, it uses the first
+        shard that is not being locked by other threads instead of waiting for
+        the locked shard to gets freed and if all of them are busy it waits on
+        the least contended one for a bounded time, the reconciler moves the
+        keys back to their home shards later.
 
+    */
+    let any_shard_writer_map = map.clone();
+    map.spawn_task("any-shard-writer", |token| async move{
+        for idx in 0..any_shard_writer_map.shard_count(){
+            if token.is_cancelled(){
+                break;
+            }
+            match any_shard_writer_map.acquire_any_shard(Duration::from_millis(100)).await{
+                Ok(mut gaurd) => {
+                    let value = format!("value is {} from shard {}", idx, gaurd.index());
+                    gaurd.insert(-(idx as i32) - 1, value);
                 },
-                Err(e) => {
-                    // use other mutex instead
-                    continue;
-                } 
+                Err(e) => tracing::warn!(idx, error = %e, "no free shard"),
             }
         }
     });
-    
-    /* 
 
-        in here we're waiting to receive the mutex data
-        from the channel asyncly in order to update shards
-        based on the largest mutex data to remove forks.  
+
+    /*
+
+        waiting to receive the latest data from the channel until all
+        the writes have been published, ctrl-c or the publish timeout, a
+        write of the any shard writer may have been dropped or overwritten
+        so we can't count on every one of them being published.
 
     */
-    tokio::spawn(async move{
-        tokio::select!{ //// instead of using while let ... syntax
-            mutex_data = mutex_data_receiver.recv() => {
-                if let Some(largest_data) = mutex_data{
-                    
-                    // check that this is the largest data
-                    if current_data_length < largest_data.len(){
-                        
-                        // update the whole shards with the largest_data
-                        let new_shards = vec![Arc::new(tokio::sync::Mutex::new(largest_data)); shards];
-
-                        // broadcast the new shards to the channel so all receivers can use the updated version
-                        map_shards_sender.send(new_shards).unwrap();
-                        
-                    } else{
-
-                        /* MEANS THAT NO MUTEX HAS BEEN MUTATED YET */
-                        // ...
-                    }
-
-                } else{
-                    
-                    /* SOMETHING WENT WRONG IN SENDING TO CHANNEL */
-                    // ...
-                }    
+    let mut current_data_length = 0;
+    tokio::select!{
+        _ = tokio::signal::ctrl_c() => {
+            println!("got ctrl-c, shutting down");
+        },
+        waited = tokio::time::timeout(PUBLISH_TIMEOUT, async{
+            while current_data_length < shards * 2{
+                match subscriber.recv().await{
+                    Ok(data) => current_data_length = data.len(),
+                    Err(_) => break,
+                }
+            }
+        }) => {
+            if waited.is_err(){
+                println!("not every write has been published after {:?}", PUBLISH_TIMEOUT);
             }
         }
-    });
+    }
 
+    println!("published {} entries out of {} shards", current_data_length, map.shard_count());
+    println!("subscriber resynced {} times after lagging behind", map.metrics().lag_resyncs());
+    println!("{} entries can be read without locking the shards", map.view_len());
+    if let Some(heat) = map.hot_shards().first(){
+        println!("shard {} is the most contended one, {} of its {} lock attempts were busy", heat.shard, heat.contended, heat.attempts);
+    }
+    let stats = map.stats();
+    let (wait, hold) = (stats.wait(), stats.hold());
+    println!(
+        "{} operations, locks waited {}ns at p99 and were held {}ns at p99",
+        stats.operations(), wait.value_at_quantile(0.99), hold.value_at_quantile(0.99)
+    );
+
+    //// the pool can be grown or shrunk while it's being used
+    let report = map.reshard(shards * 2).await?;
+    println!("resharded from {} into {} shards, {} of {} entries moved", report.from, report.to, report.moved, map.len().await?);
+
+    //// the panics of the tasks are logged by the map itself
+    let report = map.shutdown().await;
+    println!("joined {} tasks, {} of them panicked", report.joined, report.panicked.len());
+
+    if let Some(path) = &config.snapshot{
+        let saved = save_snapshot(&map, path).await?;
+        println!("saved {} entries into {}", saved, path.display());
+    }
 
 
     Ok(())
@@ -171,3 +246,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
 
 
 }
+
+
+/// the snapshot is a line of `key<TAB>value` per entry, a missing file is an empty map
+async fn load_snapshot<S, B>(map: &ShardedMap<i32, String, S, B>, path: &Path) -> Result<usize, BoxError> where
+    S: BuildHasher,
+    B: LockBackend
+{
+    let content = match tokio::fs::read_to_string(path).await{
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut loaded = 0;
+    for line in content.lines().filter(|line| !line.is_empty()){
+        let (key, value) = line.split_once('\t').ok_or_else(|| format!("malformed snapshot line: {}", line))?;
+        map.insert(key.parse()?, value.to_string()).await?;
+        loaded += 1;
+    }
+    Ok(loaded)
+}
+
+async fn save_snapshot<S, B>(map: &ShardedMap<i32, String, S, B>, path: &Path) -> Result<usize, BoxError> where
+    S: BuildHasher,
+    B: LockBackend
+{
+    let snapshot = map.snapshot().await?;
+    let content = snapshot.iter()
+        .map(|(key, versioned)| format!("{}\t{}\n", key, versioned.value))
+        .collect::<String>();
+    //// written aside first so a crash never leaves a half written snapshot behind
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, content).await?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(snapshot.len())
+}
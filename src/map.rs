@@ -0,0 +1,960 @@
+
+
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::ops::Index;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use rustc_hash::FxBuildHasher;
+use tokio::sync::{broadcast, mpsc, Mutex, Semaphore};
+use crate::affinity::{Affinity, ByGroup, ByHashTag, ByKey, Routing};
+use crate::error::{Result, S3Error};
+use crate::guard::Waiting;
+use crate::heat::HotKeys;
+use crate::lifecycle::Tasks;
+use crate::lock::{BlockingBackend, block_on, LockBackend, ShardLock, TokioMutex};
+use crate::metrics::Metrics;
+use crate::reconcile;
+use crate::placement::Placement;
+use crate::reshard::{Layout, ReshardReport};
+use crate::stats::{LockStats, Op, Timed};
+use crate::subscriber::Subscriber;
+use crate::sync::{self, Swap};
+use crate::version::{HybridClock, Version, Versioned};
+
+
+/// the data that every shard holds
+pub type Db<K, V> = HashMap<K, V>;
+
+/// the db of a single shard, every value carries the version of the write that produced it
+pub type ShardDb<K, V> = Db<K, Versioned<V>>;
+
+/// the lock of a shard for the given lock backend
+pub type ShardLockOf<K, V, B> = <B as LockBackend>::Lock<ShardDb<K, V>>;
+
+/// a single shard of the pool, a locked db instance that can be shared between threads
+pub type Shard<K, V, B = TokioMutex> = Arc<ShardLockOf<K, V, B>>;
+
+/// the write guard of a shard for the given lock backend
+pub type ShardWriteGuard<'a, K, V, B> = <ShardLockOf<K, V, B> as ShardLock<ShardDb<K, V>>>::WriteGuard<'a>;
+
+/// the read guard of a shard for the given lock backend
+pub type ShardReadGuard<'a, K, V, B> = <ShardLockOf<K, V, B> as ShardLock<ShardDb<K, V>>>::ReadGuard<'a>;
+
+/// a write guard of a shard that records how long the shard has been held
+pub(crate) type TimedWriteGuard<'a, K, V, B> = Timed<'a, ShardWriteGuard<'a, K, V, B>>;
+
+/// a shard of the pool along with the things that are tracked for it
+pub(crate) struct Slot<K, V, B> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    shard: Shard<K, V, B>,
+    waiters: AtomicUsize,
+    view: ArcSwap<ShardDb<K, V>>,
+    stats: LockStats,
+    /// set by every write, the reconciler only looks at the dirty shards
+    dirty: sync::AtomicBool,
+}
+
+impl<K, V, B> Slot<K, V, B> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    fn new() -> Self{
+        Self{
+            shard: Arc::new(ShardLock::new(HashMap::new())),
+            waiters: AtomicUsize::new(0),
+            view: ArcSwap::from_pointee(HashMap::new()),
+            stats: LockStats::default(),
+            dirty: sync::AtomicBool::new(false),
+        }
+    }
+}
+
+/// all the shards that have ever been allocated by the map, the retired ones are empty
+pub struct Shards<K, V, B = TokioMutex> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    slots: boxcar::Vec<Slot<K, V, B>>,
+}
+
+impl<K, V, B> Shards<K, V, B> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+
+    pub fn len(&self) -> usize{
+        self.slots.count()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.slots.is_empty()
+    }
+
+    /// the shard at the given index, borrowed from the map so its lock can outlive this call
+    pub fn get(&self, shard: usize) -> Option<&Shard<K, V, B>>{
+        self.slots.get(shard).map(|slot| &slot.shard)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Shard<K, V, B>>{
+        self.slots.iter().map(|(_, slot)| &slot.shard)
+    }
+
+}
+
+impl<K, V, B> Index<usize> for Shards<K, V, B> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    type Output = Shard<K, V, B>;
+
+    fn index(&self, shard: usize) -> &Self::Output{
+        &self.slots[shard].shard
+    }
+}
+
+impl<'a, K, V, B> IntoIterator for &'a Shards<K, V, B> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    type Item = &'a Shard<K, V, B>;
+    type IntoIter = Box<dyn Iterator<Item = &'a Shard<K, V, B>> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter{
+        Box::new(self.iter())
+    }
+}
+
+/// the hasher that is used to route keys when no other one is given
+pub type DefaultHashBuilder = FxBuildHasher;
+
+/// the merged data of all the shards that gets published after each reconciliation
+pub type Published<K, V> = Arc<Db<K, Versioned<V>>>;
+
+
+/*
+
+    shared state sharding to decrease the time lock, every key has exactly
+    one home shard which is selected by hashing the key with the map hasher
+    and placing the hash on a shard (see placement.rs), related keys can be
+    kept in the same shard by hash tags or an affinity (see affinity.rs), so
+    threads that are working on keys of different shards never wait for each
+    other and a read only needs to lock the home shard of its key, the
+    shards are kept inside an append only vector so the pool can be grown by
+    resharding while the shards are being borrowed (see reshard.rs), the
+    lock of the shards is picked by the lock backend of the map, by default
+    it's tokio mutex to lock on the mutex asyncly instead of using std mutex
+    which is a blocking manner (see lock.rs), every write is stamped with a
+    hybrid logical clock version so copies of the same key in different
+    shards can be told apart during the reconciliation.
+
+    after each write the mutated shard is flagged as dirty and its index
+    is sent to downside of the mpsc job queue channel, the reconciler task
+    (see reconciler.rs) takes the updates from there in batches, moves the
+    stray keys of the dirty shards back home and broadcasts the merged data
+    to all the subscribers.
+
+*/
+pub struct ShardedMap<K, V, S = DefaultHashBuilder, B = TokioMutex> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    shards: Shards<K, V, B>,
+    layout: Swap<Layout>,
+    placement: Placement,
+    affinity: Option<Affinity<K>>,
+    resharding: Mutex<()>,
+    hasher: S,
+    clock: HybridClock,
+    updates: mpsc::Sender<usize>,
+    updates_receiver: std::sync::Mutex<Option<mpsc::Receiver<usize>>>,
+    published: broadcast::Sender<Published<K, V>>,
+    batch_size: usize,
+    tasks: Tasks,
+    metrics: Metrics,
+    cursor: sync::AtomicUsize,
+    admission: Semaphore,
+    max_writers: u32,
+    copy_on_write: bool,
+    hot: Option<HotKeys<K>>,
+    replicas: ArcSwap<ShardDb<K, V>>,
+    auto_split: Option<(f64, usize)>,
+    refresh_interval: Option<Duration>,
+}
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+
+    /*
+
+        telling the reconciler that the shard has been mutated, we don't
+        wait for a free slot in the queue since a full queue means there are
+        already pending updates and the reconciler looks at every dirty shard
+        on each batch anyway, so this one won't be lost, but if the reconciler
+        is gone nobody will ever see the update.
+
+    */
+    pub fn notify(&self, shard: usize) -> Result<()>{
+        self.shards.slots[shard].dirty.store(true, Ordering::Release);
+        match self.updates.try_send(shard){
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::warn!(shard, "the updates queue is closed, the update of the shard won't be reconciled");
+                Err(S3Error::ChannelClosed)
+            },
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::trace!(shard, "the updates queue is full, the shard will be merged with the pending ones");
+                Ok(())
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+}
+
+impl<K, V> ShardedMap<K, V> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync
+{
+
+    /// builds a pool of `shard_count` empty shards, panics if `shard_count` is zero
+    pub fn new(shard_count: usize) -> Self{
+        Self::with_hasher(shard_count, DefaultHashBuilder::default())
+    }
+
+    pub fn builder() -> ShardedMapBuilder{
+        ShardedMapBuilder::new()
+    }
+
+}
+
+impl<K, V, S> ShardedMap<K, V, S> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher
+{
+
+    /// builds a pool of `shard_count` empty shards that routes keys using `hasher`
+    pub fn with_hasher(shard_count: usize, hasher: S) -> Self{
+        ShardedMapBuilder::new().shards(shard_count).hasher(hasher).build()
+    }
+
+}
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /// number of shards that the keys are routed to, the new one while resharding
+    pub fn shard_count(&self) -> usize{
+        self.layout.load().shard_count()
+    }
+
+    /// the shards that are not being retired by an ongoing resharding
+    pub(crate) fn active_shards(&self) -> usize{
+        self.layout.load().active_shards()
+    }
+
+    pub fn hasher(&self) -> &S{
+        &self.hasher
+    }
+
+    /// the clock that stamps every write of this map
+    pub fn clock(&self) -> &HybridClock{
+        &self.clock
+    }
+
+    /// the underlying shards of the pool, including the ones that have been retired by resharding
+    pub fn shards(&self) -> &Shards<K, V, B>{
+        &self.shards
+    }
+
+    pub(crate) fn shard(&self, shard: usize) -> &Shard<K, V, B>{
+        &self.shards.slots[shard].shard
+    }
+
+    /// how the keys are placed on the shards, see placement.rs
+    pub fn placement(&self) -> Placement{
+        self.placement
+    }
+
+    /// the hash of the part of the key that picks its home, see affinity.rs
+    pub(crate) fn key_hash(&self, key: &K) -> u64{
+        match &self.affinity{
+            Some(affinity) => affinity.hash(&self.hasher, key),
+            None => self.hasher.hash_one(key),
+        }
+    }
+
+    pub(crate) fn layout(&self) -> &Swap<Layout>{
+        &self.layout
+    }
+
+    pub(crate) fn resharding(&self) -> &Mutex<()>{
+        &self.resharding
+    }
+
+    /// allocates a new empty shard at the end of the pool, returns its index
+    pub(crate) fn push_shard(&self) -> usize{
+        self.shards.slots.push(Slot::new())
+    }
+
+    /// max number of updates that the reconciler merges at once
+    pub fn batch_size(&self) -> usize{
+        self.batch_size
+    }
+
+    /// max number of writers that can work on the pool at the same time
+    pub fn max_writers(&self) -> u32{
+        self.max_writers
+    }
+
+    pub(crate) fn admission(&self) -> &Semaphore{
+        &self.admission
+    }
+
+    /// index of the home shard of the key, the same key always goes to the same shard
+    pub fn shard_index(&self, key: &K) -> usize{
+        self.layout.load().route(self.key_hash(key))
+    }
+
+    /// number of tasks that are currently waiting for the lock of the shard
+    pub fn waiters(&self, shard: usize) -> &AtomicUsize{
+        &self.shards.slots[shard].waiters
+    }
+
+    pub(crate) fn view(&self, shard: usize) -> &ArcSwap<ShardDb<K, V>>{
+        &self.shards.slots[shard].view
+    }
+
+    pub(crate) fn views(&self) -> impl Iterator<Item = &ArcSwap<ShardDb<K, V>>>{
+        self.shards.slots.iter().map(|(_, slot)| &slot.view)
+    }
+
+    pub fn copy_on_write(&self) -> bool{
+        self.copy_on_write
+    }
+
+    pub(crate) fn hot(&self) -> Option<&HotKeys<K>>{
+        self.hot.as_ref()
+    }
+
+    pub(crate) fn replicas(&self) -> &ArcSwap<ShardDb<K, V>>{
+        &self.replicas
+    }
+
+    pub(crate) fn lock_stats(&self, shard: usize) -> &LockStats{
+        &self.shards.slots[shard].stats
+    }
+
+    /// the contention and the number of parts that the reconciler splits the hot shards with
+    pub fn auto_split(&self) -> Option<(f64, usize)>{
+        self.auto_split
+    }
+
+    /// how often the reconciler refreshes the whole pool, never by default
+    pub fn refresh_interval(&self) -> Option<Duration>{
+        self.refresh_interval
+    }
+
+    pub(crate) fn next_cursor(&self) -> usize{
+        self.cursor.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// write locks the shard, the caller is counted as a waiter of the shard while the shard is busy
+    pub(crate) async fn lock_shard(&self, shard: usize) -> Result<TimedWriteGuard<'_, K, V, B>>{
+        let poisoned = |_| S3Error::ShardPoisoned{shard};
+        let slot = &self.shards.slots[shard];
+        let since = std::time::Instant::now();
+        if let Some(gaurd) = slot.shard.try_write().map_err(poisoned)?{
+            slot.stats.record(false, since.elapsed());
+            return Ok(Timed::new(gaurd, &slot.stats));
+        }
+        let _waiting = Waiting::new(&slot.waiters);
+        let gaurd = slot.shard.write().await.map_err(poisoned)?;
+        slot.stats.record(true, since.elapsed());
+        Ok(Timed::new(gaurd, &slot.stats))
+    }
+
+    /// read locks the shard, the rwlock backends let the readers share it
+    pub(crate) async fn read_shard(&self, shard: usize) -> Result<Timed<'_, ShardReadGuard<'_, K, V, B>>>{
+        let slot = &self.shards.slots[shard];
+        let since = std::time::Instant::now();
+        let gaurd = slot.shard.read().await.map_err(|_| S3Error::ShardPoisoned{shard})?;
+        slot.stats.record_read(since.elapsed());
+        Ok(Timed::new(gaurd, &slot.stats))
+    }
+
+    /*
+
+        the home shard of a key may change while we're waiting for its lock
+        since a resharding may have moved the key to its new home in the
+        meantime, so the home is checked again once the lock is acquired and
+        we'll go after the new home if it has been changed.
+
+    */
+    pub(crate) async fn lock_home(&self, key: &K) -> Result<(usize, TimedWriteGuard<'_, K, V, B>)>{
+        loop{
+            let idx = self.shard_index(key);
+            let gaurd = self.lock_shard(idx).await?;
+            if self.shard_index(key) == idx{
+                tracing::Span::current().record("shard", idx);
+                return Ok((idx, gaurd));
+            }
+            tracing::trace!(shard = idx, "the key has moved to a new home while waiting for its shard");
+        }
+    }
+
+    async fn read_home(&self, key: &K) -> Result<(usize, Timed<'_, ShardReadGuard<'_, K, V, B>>)>{
+        loop{
+            let idx = self.shard_index(key);
+            let gaurd = self.read_shard(idx).await?;
+            if self.shard_index(key) == idx{
+                tracing::Span::current().record("shard", idx);
+                return Ok((idx, gaurd));
+            }
+            tracing::trace!(shard = idx, "the key has moved to a new home while waiting for its shard");
+        }
+    }
+
+    pub async fn get(&self, key: &K) -> Result<V>{
+        self.get_versioned(key).await.map(|versioned| versioned.value)
+    }
+
+    /// the value of the key along with the version of the write that produced it
+    #[tracing::instrument(level = "trace", name = "get", skip_all, fields(key_hash = self.key_hash(key), shard))]
+    pub async fn get_versioned(&self, key: &K) -> Result<Versioned<V>>{
+        self.record_access(key);
+        if let Some(replica) = self.replica(key){
+            let idx = self.shard_index(key);
+            tracing::trace!(shard = idx, "read from the replica of the hot key");
+            self.lock_stats(idx).record_op(Op::Get);
+            return Ok(replica);
+        }
+        let (idx, gaurd) = self.read_home(key).await?;
+        self.lock_stats(idx).record_op(Op::Get);
+        gaurd.get(key)
+            .cloned()
+            .ok_or(S3Error::KeyNotFound)
+    }
+
+    /// inserts the value and returns the old one, fails without writing anything once the map has been shut down
+    pub async fn insert(&self, key: K, value: V) -> Result<Option<V>>{
+        self.insert_versioned(key, value).await.map(|(old, _)| old)
+    }
+
+    /// inserts the value and returns the old value along with the version of this write
+    #[tracing::instrument(level = "trace", name = "insert", skip_all, fields(key_hash = self.key_hash(&key), shard))]
+    pub async fn insert_versioned(&self, key: K, value: V) -> Result<(Option<V>, Version)>{
+        self.ensure_open()?;
+        let _permit = self.write_permit().await?;
+        self.record_access(&key);
+        let (idx, mut gaurd) = self.lock_home(&key).await?;
+        self.lock_stats(idx).record_op(Op::Insert);
+        let version = self.clock.now();
+        let value = Versioned::new(value, version);
+        self.update_replica(&key, Some(&value));
+        let old = gaurd.insert(key, value);
+        self.publish_view(idx, &gaurd);
+        drop(gaurd);
+        //// the write is stored, a queue that got closed since ensure_open() only means it won't be reconciled
+        let _ = self.notify(idx);
+        Ok((old.map(|versioned| versioned.value), version))
+    }
+
+    #[tracing::instrument(level = "trace", skip_all, fields(key_hash = self.key_hash(key), shard))]
+    pub async fn remove(&self, key: &K) -> Result<V>{
+        self.ensure_open()?;
+        let _permit = self.write_permit().await?;
+        self.record_access(key);
+        let (idx, mut gaurd) = self.lock_home(key).await?;
+        self.lock_stats(idx).record_op(Op::Remove);
+        let removed = gaurd.remove(key).ok_or(S3Error::KeyNotFound)?;
+        self.update_replica(key, None);
+        self.publish_view(idx, &gaurd);
+        drop(gaurd);
+        let _ = self.notify(idx);
+        Ok(removed.value)
+    }
+
+    /// the reconciler drops the queue once the map has been shut down
+    pub(crate) fn ensure_open(&self) -> Result<()>{
+        if self.updates.is_closed(){
+            return Err(S3Error::ChannelClosed);
+        }
+        Ok(())
+    }
+
+    /// takes the receiving half of the updates queue, there is only one of it per map
+    pub(crate) fn take_updates_receiver(&self) -> Option<mpsc::Receiver<usize>>{
+        self.updates_receiver.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    pub(crate) fn tasks(&self) -> &Tasks{
+        &self.tasks
+    }
+
+    pub fn metrics(&self) -> &Metrics{
+        &self.metrics
+    }
+
+    /// number of updates that are waiting inside the mpsc queue for the reconciler
+    pub fn queued_updates(&self) -> usize{
+        self.updates.max_capacity() - self.updates.capacity()
+    }
+
+    /// number of published merges that some subscribers haven't received yet
+    pub fn queued_publishes(&self) -> usize{
+        self.published.len()
+    }
+
+    pub fn subscriber_count(&self) -> usize{
+        self.published.receiver_count()
+    }
+
+    /// a receiver of the merged data that is published after each reconciliation
+    pub fn subscribe(self: &Arc<Self>) -> Subscriber<K, V, S, B>{
+        Subscriber::new(self, self.published.subscribe())
+    }
+
+    /// broadcasts the merged data to all the subscribers, returns the number of them
+    pub fn publish(&self, merged: Db<K, Versioned<V>>) -> usize{
+        match self.published.send(Arc::new(merged)){
+            Ok(subscribers) => subscribers,
+            Err(_) => {
+                tracing::trace!("nobody has subscribed to the merged data");
+                0
+            }
+        }
+    }
+
+    /// total number of entries stored inside all the shards
+    pub async fn len(&self) -> Result<usize>{
+        let mut len = 0;
+        for shard in 0..self.shards.slots.count(){
+            len += self.read_shard(shard).await?.len();
+        }
+        Ok(len)
+    }
+
+    pub async fn is_empty(&self) -> Result<bool>{
+        Ok(self.len().await? == 0)
+    }
+
+    /*
+
+        reconciling the pool by merging all the shards into a single db, the
+        locks are acquired in shard order and held until every shard has been
+        updated with its own part of the merged data so no writer can sneak in
+        between the merge and the republish and get lost, keys that have been
+        put inside a shard other than their home will be moved back to it, the
+        reconciliation doesn't run while the pool is being resharded.
+
+    */
+    #[tracing::instrument(level = "debug", skip_all, fields(shards = self.shard_count()))]
+    pub async fn reconcile(&self) -> Result<Db<K, Versioned<V>>>{
+        let _resharding = self.resharding.lock().await;
+        let locks = self.shards.slots.iter().map(|(_, slot)| &*slot.shard).collect::<Vec<_>>();
+        let mut gaurds = ShardLockOf::<K, V, B>::write_all(&locks).await?;
+
+        let home = |key: &K| self.shard_index(key);
+        let merged = reconcile::merge(gaurds.iter().map(|g| &**g).enumerate(), home);
+        let parts = reconcile::partition(&merged, self.shard_count(), home);
+        //// the retired shards get emptied
+        let parts = parts.into_iter().chain(std::iter::repeat_with(HashMap::new));
+        for (idx, (gaurd, part)) in gaurds.iter_mut().zip(parts).enumerate(){
+            self.store_view(idx, &part);
+            **gaurd = part;
+        }
+        self.sync_replicas(&merged, |_| true);
+        self.metrics.record_reconciliation();
+        tracing::debug!(entries = merged.len(), "reconciled the shards");
+
+        Ok(merged)
+    }
+
+    /*
+
+        reconciling only the shards that have been written since their last
+        reconciliation, the keys that have been put inside a shard other than
+        their home are moved back to it and the views of the touched shards
+        are refreshed, a shard is never held while waiting for the lock of
+        another one and the clean shards are not locked at all so the readers
+        keep going, no writer is admitted while the keys are being moved like
+        during a refresh, the whole pool is only merged by reconcile().
+
+    */
+    #[tracing::instrument(level = "debug", skip_all, fields(shards))]
+    pub async fn reconcile_dirty(&self) -> Result<usize>{
+        let _all = self.admit_none().await?;
+        let _resharding = self.resharding.lock().await;
+        let dirty = self.shards.slots.iter()
+            .filter(|(_, slot)| slot.dirty.swap(false, Ordering::AcqRel))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        tracing::Span::current().record("shards", dirty.len());
+
+        let mut moved = 0;
+        for (n, &shard) in dirty.iter().enumerate(){
+            match self.reconcile_shard(shard).await{
+                Ok(strays) => moved += strays,
+                Err(e) => {
+                    //// they'll be tried again on the next batch
+                    for &shard in &dirty[n..]{
+                        self.shards.slots[shard].dirty.store(true, Ordering::Release);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.metrics.record_reconciliation();
+        tracing::debug!(moved, "reconciled the dirty shards");
+        Ok(moved)
+    }
+
+    /// moves the stray keys of the shard to their home, returns the number of them
+    async fn reconcile_shard(&self, shard: usize) -> Result<usize>{
+        let mut strays = BTreeMap::<usize, Vec<(K, Versioned<V>)>>::new();
+        {
+            let mut gaurd = self.lock_shard(shard).await?;
+            for (key, value) in gaurd.extract_if(|key, _| self.shard_index(key) != shard){
+                strays.entry(self.shard_index(&key)).or_default().push((key, value));
+            }
+            self.store_view(shard, &gaurd);
+        }
+
+        let mut moved = 0;
+        let mut strays = strays.into_iter();
+        let failed = loop{
+            let Some((home, entries)) = strays.next() else{
+                return Ok(moved);
+            };
+            let mut gaurd = match self.lock_shard(home).await{
+                Ok(gaurd) => gaurd,
+                Err(e) => break (e, entries),
+            };
+            moved += entries.len();
+            for (key, value) in entries{
+                reconcile::keep_latest(&mut gaurd, key, value);
+            }
+            self.store_view(home, &gaurd);
+            self.sync_replicas(&gaurd, |key| self.shard_index(key) == home);
+        };
+
+        //// back where they were so they're not lost with the poisoned home
+        let (e, entries) = failed;
+        let mut source = self.lock_shard(shard).await?;
+        for (key, value) in entries.into_iter().chain(strays.flat_map(|(_, entries)| entries)){
+            reconcile::keep_latest(&mut source, key, value);
+        }
+        self.store_view(shard, &source);
+        Err(e)
+    }
+
+    /// a single db of all the shards merged like a reconciliation does, each shard is read on its own
+    pub async fn snapshot(&self) -> Result<Db<K, Versioned<V>>>{
+        let mut shards = Vec::with_capacity(self.shards.slots.count());
+        for shard in 0..self.shards.slots.count(){
+            let gaurd = self.read_shard(shard).await?;
+            shards.push(ShardDb::clone(&gaurd));
+        }
+        Ok(reconcile::merge(shards.iter().enumerate(), |key| self.shard_index(key)))
+    }
+
+}
+
+
+/*
+
+    the blocking api of the maps with a blocking lock backend (std and
+    parking_lot locks) so sync code can use the map without a runtime,
+    the writers still have to wait for the refresh phase of the pool.
+
+*/
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: BlockingBackend
+{
+
+    pub fn get_blocking(&self, key: &K) -> Result<V>{
+        block_on(self.get(key))
+    }
+
+    pub fn insert_blocking(&self, key: K, value: V) -> Result<Option<V>>{
+        block_on(self.insert(key, value))
+    }
+
+    pub fn remove_blocking(&self, key: &K) -> Result<V>{
+        block_on(self.remove(key))
+    }
+
+    pub fn len_blocking(&self) -> Result<usize>{
+        block_on(self.len())
+    }
+
+    pub fn reconcile_blocking(&self) -> Result<Db<K, Versioned<V>>>{
+        block_on(self.refresh())
+    }
+
+    pub fn reshard_blocking(&self, shard_count: usize) -> Result<ReshardReport>{
+        block_on(self.reshard(shard_count))
+    }
+
+}
+
+
+
+/// builds a sharded map, the channel capacity defaults to the number of shards
+#[derive(Clone, Debug)]
+pub struct ShardedMapBuilder<S = DefaultHashBuilder, B = TokioMutex, R = ByKey>{
+    shard_count: usize,
+    updates_capacity: Option<usize>,
+    publish_capacity: Option<usize>,
+    batch_size: usize,
+    max_writers: u32,
+    node: u32,
+    copy_on_write: bool,
+    hot_keys: usize,
+    auto_split: Option<(f64, usize)>,
+    refresh_interval: Option<Duration>,
+    placement: Placement,
+    routing: R,
+    hasher: S,
+    backend: PhantomData<B>,
+}
+
+impl ShardedMapBuilder{
+
+    pub fn new() -> Self{
+        Self{
+            shard_count: 10,
+            updates_capacity: None,
+            publish_capacity: None,
+            batch_size: 64,
+            max_writers: 1024,
+            node: 0,
+            copy_on_write: false,
+            hot_keys: 0,
+            auto_split: None,
+            refresh_interval: None,
+            placement: Placement::Modulo,
+            routing: ByKey,
+            hasher: DefaultHashBuilder::default(),
+            backend: PhantomData,
+        }
+    }
+
+}
+
+impl Default for ShardedMapBuilder{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl<S, B, R> ShardedMapBuilder<S, B, R>{
+
+    pub fn shards(mut self, shard_count: usize) -> Self{
+        self.shard_count = shard_count;
+        self
+    }
+
+    /// capacity of the mpsc updates queue and the broadcast channel
+    pub fn channel_capacity(self, capacity: usize) -> Self{
+        self.updates_capacity(capacity).publish_capacity(capacity)
+    }
+
+    /// capacity of the mpsc updates queue, defaults to the number of shards
+    pub fn updates_capacity(mut self, capacity: usize) -> Self{
+        self.updates_capacity = Some(capacity);
+        self
+    }
+
+    /// capacity of the broadcast channel of the merged data, defaults to the number of shards
+    pub fn publish_capacity(mut self, capacity: usize) -> Self{
+        self.publish_capacity = Some(capacity);
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self{
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// max number of writers that are admitted into the pool at the same time
+    pub fn max_writers(mut self, max_writers: u32) -> Self{
+        self.max_writers = max_writers;
+        self
+    }
+
+    /// node id of the clock that stamps the writes
+    pub fn node(mut self, node: u32) -> Self{
+        self.node = node;
+        self
+    }
+
+    /// publishes a new view of the shard on every write instead of only on each reconciliation
+    pub fn copy_on_write(mut self, enabled: bool) -> Self{
+        self.copy_on_write = enabled;
+        self
+    }
+
+    /// tracks the accesses of the keys to find this number of hot keys, nothing is tracked by default
+    pub fn hot_keys(mut self, capacity: usize) -> Self{
+        self.hot_keys = capacity;
+        self
+    }
+
+    /// lets the reconciler split the shards that have more than `contention` of their locks contended
+    pub fn auto_split(mut self, contention: f64, parts: usize) -> Self{
+        assert!(parts > 1, "a shard must be split into at least two parts");
+        self.auto_split = Some((contention, parts));
+        self
+    }
+
+    /// lets the reconciler merge and refresh the whole pool at this interval on top of its batches
+    pub fn refresh_interval(mut self, interval: Duration) -> Self{
+        assert!(!interval.is_zero(), "the refresh interval must not be zero");
+        self.refresh_interval = Some(interval);
+        self
+    }
+
+    /// how the keys are placed on the shards, the modulo placement is the default
+    pub fn placement(mut self, placement: Placement) -> Self{
+        self.placement = placement;
+        self
+    }
+
+    /// routes the keys by their hash tag, the keys without a tag are routed by themselves
+    pub fn hash_tags(self) -> ShardedMapBuilder<S, B, ByHashTag>{
+        self.routing(ByHashTag)
+    }
+
+    /// routes the keys by their group, the keys without a group are routed by themselves
+    pub fn affinity<F>(self, group: F) -> ShardedMapBuilder<S, B, ByGroup<F>>{
+        self.routing(ByGroup(group))
+    }
+
+    fn routing<T>(self, routing: T) -> ShardedMapBuilder<S, B, T>{
+        ShardedMapBuilder{
+            shard_count: self.shard_count,
+            updates_capacity: self.updates_capacity,
+            publish_capacity: self.publish_capacity,
+            batch_size: self.batch_size,
+            max_writers: self.max_writers,
+            node: self.node,
+            copy_on_write: self.copy_on_write,
+            hot_keys: self.hot_keys,
+            auto_split: self.auto_split,
+            refresh_interval: self.refresh_interval,
+            placement: self.placement,
+            routing,
+            hasher: self.hasher,
+            backend: PhantomData,
+        }
+    }
+
+    pub fn hasher<H: BuildHasher>(self, hasher: H) -> ShardedMapBuilder<H, B, R>{
+        ShardedMapBuilder{
+            shard_count: self.shard_count,
+            updates_capacity: self.updates_capacity,
+            publish_capacity: self.publish_capacity,
+            batch_size: self.batch_size,
+            max_writers: self.max_writers,
+            node: self.node,
+            copy_on_write: self.copy_on_write,
+            hot_keys: self.hot_keys,
+            auto_split: self.auto_split,
+            refresh_interval: self.refresh_interval,
+            placement: self.placement,
+            routing: self.routing,
+            hasher,
+            backend: PhantomData,
+        }
+    }
+
+    /// the lock backend of the shards, see lock.rs
+    pub fn backend<L: LockBackend>(self) -> ShardedMapBuilder<S, L, R>{
+        ShardedMapBuilder{
+            shard_count: self.shard_count,
+            updates_capacity: self.updates_capacity,
+            publish_capacity: self.publish_capacity,
+            batch_size: self.batch_size,
+            max_writers: self.max_writers,
+            node: self.node,
+            copy_on_write: self.copy_on_write,
+            hot_keys: self.hot_keys,
+            auto_split: self.auto_split,
+            refresh_interval: self.refresh_interval,
+            placement: self.placement,
+            routing: self.routing,
+            hasher: self.hasher,
+            backend: PhantomData,
+        }
+    }
+
+    /// panics if the shard count, a channel capacity, the batch size or the max writers is zero
+    pub fn build<K, V>(self) -> ShardedMap<K, V, S, B> where
+        K: Eq + Hash + Clone + Send + Sync,
+        V: Clone + Send + Sync,
+        S: BuildHasher,
+        B: LockBackend,
+        R: Routing<K>
+    {
+        assert!(self.shard_count > 0, "a sharded map needs at least one shard");
+        assert!(self.batch_size > 0, "the batch size must be at least one");
+        assert!(self.max_writers > 0, "at least one writer must be admitted");
+        let updates_capacity = self.updates_capacity.unwrap_or(self.shard_count);
+        let publish_capacity = self.publish_capacity.unwrap_or(self.shard_count);
+        assert!(updates_capacity > 0 && publish_capacity > 0, "the channel capacity must be at least one");
+
+        let shards = Shards{slots: (0..self.shard_count).map(|_| Slot::new()).collect()};
+        let (updates, updates_receiver) = mpsc::channel(updates_capacity);
+        let (published, _) = broadcast::channel(publish_capacity);
+
+        ShardedMap{
+            shards,
+            layout: Swap::from_pointee(Layout::stable(Arc::new(self.placement.router(self.shard_count)))),
+            placement: self.placement,
+            affinity: self.routing.affinity(),
+            resharding: Mutex::new(()),
+            hasher: self.hasher,
+            clock: HybridClock::new(self.node),
+            updates,
+            updates_receiver: std::sync::Mutex::new(Some(updates_receiver)),
+            published,
+            batch_size: self.batch_size,
+            tasks: Tasks::default(),
+            metrics: Metrics::default(),
+            cursor: sync::AtomicUsize::new(0),
+            admission: Semaphore::new(self.max_writers as usize),
+            max_writers: self.max_writers,
+            copy_on_write: self.copy_on_write,
+            hot: (self.hot_keys > 0).then(|| HotKeys::new(self.hot_keys)),
+            replicas: ArcSwap::default(),
+            auto_split: self.auto_split,
+            refresh_interval: self.refresh_interval,
+        }
+    }
+
+}
@@ -0,0 +1,50 @@
+
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// counters of the things that happen inside the map over its lifetime
+#[derive(Debug, Default)]
+pub struct Metrics{
+    reconciliations: AtomicU64,
+    lag_resyncs: AtomicU64,
+    reshards: AtomicU64,
+    moved_keys: AtomicU64,
+}
+
+impl Metrics{
+
+    /// number of times the shards have been merged and put back to their homes
+    pub fn reconciliations(&self) -> u64{
+        self.reconciliations.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_reconciliation(&self){
+        self.reconciliations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// number of times a subscriber has lagged behind and resynced from a full snapshot
+    pub fn lag_resyncs(&self) -> u64{
+        self.lag_resyncs.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_lag_resync(&self){
+        self.lag_resyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// number of times the shard count has been changed
+    pub fn reshards(&self) -> u64{
+        self.reshards.load(Ordering::Relaxed)
+    }
+
+    /// number of keys that have been moved to a new home by all the reshardings
+    pub fn moved_keys(&self) -> u64{
+        self.moved_keys.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_reshard(&self, moved: usize){
+        self.reshards.fetch_add(1, Ordering::Relaxed);
+        self.moved_keys.fetch_add(moved as u64, Ordering::Relaxed);
+    }
+
+}
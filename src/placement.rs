@@ -0,0 +1,131 @@
+
+
+
+use std::sync::Arc;
+
+
+/*
+
+    how the keys are placed on the shards, the modulo placement is the
+    cheapest one but changing the number of shards moves almost every key
+    to a new home, the consistent hashing ring only moves the keys of the
+    ring arcs that have been taken by the new shards or freed by the removed
+    ones, more virtual nodes per shard spread the keys more evenly, the
+    rendezvous (highest random weight) placement moves the least number of
+    keys without any ring to build but it scores every shard on each lookup,
+    a single hot shard can also be split on top of any placement, the split
+    is dropped once the pool gets resharded.
+
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement{
+    #[default]
+    Modulo,
+    Ring{vnodes: usize},
+    Rendezvous,
+}
+
+impl Placement{
+
+    /// a ring with the given number of virtual nodes per shard
+    pub fn ring(vnodes: usize) -> Self{
+        assert!(vnodes > 0, "a shard needs at least one virtual node on the ring");
+        Placement::Ring{vnodes}
+    }
+
+    pub(crate) fn router(&self, shard_count: usize) -> Router{
+        match *self{
+            Placement::Modulo => Router::Modulo(shard_count),
+            Placement::Ring{vnodes} => Router::Ring(Ring::new(shard_count, vnodes)),
+            Placement::Rendezvous => Router::Rendezvous(shard_count),
+        }
+    }
+
+}
+
+
+/// maps the hash of a key to its shard for a fixed number of shards
+pub(crate) enum Router{
+    Modulo(usize),
+    Ring(Ring),
+    Rendezvous(usize),
+    /// the keys of the split shard are spread over the shard itself and its sub shards
+    Split{base: Arc<Router>, shard: usize, subs: Vec<usize>},
+}
+
+impl Router{
+
+    pub(crate) fn route(&self, hash: u64) -> usize{
+        match self{
+            Router::Modulo(shard_count) => (hash % *shard_count as u64) as usize,
+            Router::Ring(ring) => ring.route(mix(hash)),
+            Router::Rendezvous(shard_count) => {
+                let hash = mix(hash);
+                (0..*shard_count)
+                    .max_by_key(|&shard| mix(hash ^ mix(shard as u64)))
+                    .unwrap_or(0)
+            }
+            Router::Split{base, shard, subs} => {
+                let home = base.route(hash);
+                if home != *shard{
+                    return home;
+                }
+                //// salted by the first sub shard so splitting the same shard twice spreads its keys again
+                match (mix(hash.rotate_left(29) ^ mix(subs[0] as u64)) % (subs.len() as u64 + 1)) as usize{
+                    0 => *shard,
+                    sub => subs[sub - 1],
+                }
+            }
+        }
+    }
+
+    /// splits the shard into `parts` shards, the new ones are put right after the current ones
+    pub(crate) fn split(self: &Arc<Self>, shard: usize, parts: usize) -> Router{
+        let shard_count = self.shard_count();
+        Router::Split{base: self.clone(), shard, subs: (shard_count..shard_count + parts - 1).collect()}
+    }
+
+    pub(crate) fn shard_count(&self) -> usize{
+        match self{
+            Router::Modulo(shard_count) | Router::Rendezvous(shard_count) => *shard_count,
+            Router::Ring(ring) => ring.shard_count,
+            Router::Split{base, subs, ..} => base.shard_count() + subs.len(),
+        }
+    }
+
+}
+
+
+/// the points of all the virtual nodes sorted around the ring
+pub(crate) struct Ring{
+    points: Vec<(u64, usize)>,
+    shard_count: usize,
+}
+
+impl Ring{
+
+    fn new(shard_count: usize, vnodes: usize) -> Self{
+        let mut points = (0..shard_count)
+            .flat_map(|shard| (0..vnodes).map(move |vnode| (mix(((shard as u64) << 32) | vnode as u64), shard)))
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        Self{points, shard_count}
+    }
+
+    /// the first virtual node that comes after the point clockwise
+    fn route(&self, point: u64) -> usize{
+        let idx = self.points.partition_point(|&(p, _)| p < point);
+        self.points.get(idx).unwrap_or(&self.points[0]).1
+    }
+
+}
+
+
+/// the splitmix64 finalizer, it spreads the bits of the hashes and the virtual nodes
+pub(crate) fn mix(mut x: u64) -> u64{
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
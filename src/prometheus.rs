@@ -0,0 +1,223 @@
+
+
+
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hash};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use hdrhistogram::Histogram;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinSet;
+use crate::error::Result;
+use crate::lock::LockBackend;
+use crate::map::ShardedMap;
+
+
+/// the requests are dropped if their head doesn't fit in here
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// the connections that haven't sent their request head by then are dropped
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+
+/*
+
+    the metrics of the map in the prometheus text exposition format, the
+    shard sizes, the per shard operations and lock stats, the depth of the
+    updates queue and the publish channel, the reconciliations, the lags of
+    the subscribers and the reshardings, the lock latencies are exported as
+    summaries in seconds.
+
+*/
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    pub async fn prometheus(&self) -> Result<String>{
+        let mut out = String::new();
+        let stats = self.stats();
+
+        family(&mut out, "s3_shard_entries", "gauge", "Number of entries stored in the shard.");
+        for shard in 0..self.shard_count(){
+            let entries = self.read_shard(shard).await?.len();
+            let _ = writeln!(out, "s3_shard_entries{{shard=\"{}\"}} {}", shard, entries);
+        }
+
+        family(&mut out, "s3_shard_operations_total", "counter", "Number of operations that have been done on the shard.");
+        for shard in &stats.shards{
+            for (op, count) in [("get", shard.gets), ("insert", shard.inserts), ("remove", shard.removes)]{
+                let _ = writeln!(out, "s3_shard_operations_total{{shard=\"{}\",op=\"{}\"}} {}", shard.shard, op, count);
+            }
+        }
+
+        family(&mut out, "s3_shard_lock_acquisitions_total", "counter", "Number of times the shard has been write locked.");
+        for shard in &stats.shards{
+            let _ = writeln!(out, "s3_shard_lock_acquisitions_total{{shard=\"{}\"}} {}", shard.shard, shard.acquisitions);
+        }
+
+        family(&mut out, "s3_shard_try_lock_failures_total", "counter", "Number of times the shard was already locked when someone tried to lock it.");
+        for shard in &stats.shards{
+            let _ = writeln!(out, "s3_shard_try_lock_failures_total{{shard=\"{}\"}} {}", shard.shard, shard.try_lock_failures);
+        }
+
+        family(&mut out, "s3_shard_lock_wait_seconds", "summary", "Time it took to acquire the lock of the shard.");
+        for shard in &stats.shards{
+            summary(&mut out, "s3_shard_lock_wait_seconds", shard.shard, &shard.wait);
+        }
+
+        family(&mut out, "s3_shard_lock_hold_seconds", "summary", "Time the lock of the shard has been held.");
+        for shard in &stats.shards{
+            summary(&mut out, "s3_shard_lock_hold_seconds", shard.shard, &shard.hold);
+        }
+
+        let metrics = self.metrics();
+        gauge(&mut out, "s3_updates_queued", "Number of updates waiting in the mpsc queue for the reconciler.", self.queued_updates() as u64);
+        gauge(&mut out, "s3_publishes_queued", "Number of published merges that some subscribers haven't received yet.", self.queued_publishes() as u64);
+        gauge(&mut out, "s3_subscribers", "Number of subscribers of the published merges.", self.subscriber_count() as u64);
+        gauge(&mut out, "s3_shards", "Number of shards the keys are routed to.", self.shard_count() as u64);
+        counter(&mut out, "s3_reconciliations_total", "Number of times the shards have been reconciled.", metrics.reconciliations());
+        counter(&mut out, "s3_lag_resyncs_total", "Number of times a subscriber has lagged behind and resynced.", metrics.lag_resyncs());
+        counter(&mut out, "s3_reshards_total", "Number of times the shard count has been changed.", metrics.reshards());
+        counter(&mut out, "s3_moved_keys_total", "Number of keys moved to a new home by the reshardings.", metrics.moved_keys());
+
+        Ok(out)
+    }
+
+}
+
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+
+    /*
+
+        serves the metrics of the map at `GET /metrics` over plain http, the
+        server is a task of the map so it stops once the map gets shut down,
+        the connections are tasks of the server so they're cancelled and
+        awaited along with it, returns the address it's listening on which
+        is handy with port 0.
+
+    */
+    pub async fn serve_metrics(self: &Arc<Self>, addr: impl ToSocketAddrs) -> std::io::Result<SocketAddr>{
+        let listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        let map = self.clone();
+        self.spawn_task("metrics", move |token| async move{
+            let mut connections = JoinSet::new();
+            loop{
+                tokio::select!{
+                    _ = token.cancelled() => break,
+                    Some(served) = connections.join_next() => {
+                        if let Err(e) = served{
+                            tracing::warn!(error = %e, "a metrics connection has failed");
+                        }
+                    },
+                    accepted = listener.accept() => {
+                        let stream = match accepted{
+                            Ok((stream, _)) => stream,
+                            Err(e) => {
+                                tracing::warn!(error = %e, "failed to accept a metrics connection");
+                                continue;
+                            }
+                        };
+                        let (map, token) = (map.clone(), token.clone());
+                        connections.spawn(async move{
+                            tokio::select!{
+                                _ = token.cancelled() => {},
+                                served = map.respond(stream) => {
+                                    if let Err(e) = served{
+                                        tracing::debug!(error = %e, "failed to serve the metrics");
+                                    }
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+            //// the open connections have seen the same token
+            while connections.join_next().await.is_some(){}
+        });
+        Ok(local)
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()>{
+        let head = tokio::time::timeout(REQUEST_HEAD_TIMEOUT, read_head(&mut stream))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "the request head has not been sent in time"))??;
+        let Some(head) = head else{
+            return Ok(());
+        };
+
+        let head = String::from_utf8_lossy(&head);
+        let mut line = head.lines().next().unwrap_or_default().split_whitespace();
+        let response = match (line.next(), line.next()){
+            (Some("GET"), Some("/metrics")) => match self.prometheus().await{
+                Ok(body) => response("200 OK", CONTENT_TYPE, &body),
+                Err(e) => response("500 Internal Server Error", "text/plain", &e.to_string()),
+            },
+            (Some("GET"), _) => response("404 Not Found", "text/plain", "not found"),
+            _ => response("405 Method Not Allowed", "text/plain", "method not allowed"),
+        };
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+}
+
+
+/// the request line and the headers, None if the client has left or sent too much
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>>{
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n"){
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || head.len() + read > MAX_REQUEST_HEAD{
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    Ok(Some(head))
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String{
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    )
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str){
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64){
+    family(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64){
+    family(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// the histograms are in nanoseconds and prometheus wants seconds
+fn summary(out: &mut String, name: &str, shard: usize, histogram: &Histogram<u64>){
+    for quantile in QUANTILES{
+        let seconds = histogram.value_at_quantile(quantile) as f64 / 1e9;
+        let _ = writeln!(out, "{}{{shard=\"{}\",quantile=\"{}\"}} {}", name, shard, quantile, seconds);
+    }
+    let sum = histogram.mean() * histogram.len() as f64 / 1e9;
+    let _ = writeln!(out, "{}_sum{{shard=\"{}\"}} {}", name, shard, sum);
+    let _ = writeln!(out, "{}_count{{shard=\"{}\"}} {}", name, shard, histogram.len());
+}
@@ -0,0 +1,79 @@
+
+
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use crate::{Db, Versioned};
+
+
+/*
+
+    merging the content of all the shards into a single db, every key of
+    every shard ends up inside the merged db so no write will be thrown away,
+    when a key is found in more than one shard (a writer has put it inside a
+    shard which is not its home) the conflict gets resolved like so:
+
+        - the copy with the highest version wins (last writer wins)
+        - on the same version the copy that lives inside the home shard wins
+        - otherwise the copy of the shard with the lowest index wins
+
+*/
+pub fn merge<'s, K, V, I, F>(shards: I, home: F) -> Db<K, Versioned<V>> where
+    K: Eq + Hash + Clone + 's,
+    V: Clone + 's,
+    I: IntoIterator<Item = (usize, &'s Db<K, Versioned<V>>)>,
+    F: Fn(&K) -> usize
+{
+    let mut merged: HashMap<K, (usize, &Versioned<V>)> = HashMap::new();
+    for (idx, db) in shards{
+        for (key, value) in db{
+            match merged.entry(key.clone()){
+                Entry::Vacant(entry) => {
+                    entry.insert((idx, value));
+                },
+                Entry::Occupied(mut entry) => {
+                    let (current_idx, current) = *entry.get();
+                    let wins = if value.version != current.version{
+                        value.version > current.version
+                    } else{
+                        let home = home(key);
+                        current_idx != home && (idx == home || idx < current_idx)
+                    };
+                    if wins{
+                        entry.insert((idx, value));
+                    }
+                }
+            }
+        }
+    }
+    merged.into_iter().map(|(key, (_, value))| (key, value.clone())).collect()
+}
+
+/// splits the merged db back into one db per shard based on the home of each key
+pub fn partition<K, V, F>(merged: &Db<K, V>, shard_count: usize, home: F) -> Vec<Db<K, V>> where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    F: Fn(&K) -> usize
+{
+    let mut shards = vec![HashMap::new(); shard_count];
+    for (key, value) in merged{
+        shards[home(key)].insert(key.clone(), value.clone());
+    }
+    shards
+}
+
+/// puts the copy into the db unless the db already holds a copy of the key that is at least as recent
+pub(crate) fn keep_latest<K, V>(db: &mut Db<K, Versioned<V>>, key: K, value: Versioned<V>) where
+    K: Eq + Hash
+{
+    match db.entry(key){
+        Entry::Occupied(entry) if entry.get().version >= value.version => {},
+        Entry::Occupied(mut entry) => {
+            entry.insert(value);
+        },
+        Entry::Vacant(entry) => {
+            entry.insert(value);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+
+
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use crate::error::{Result, S3Error};
+use crate::lock::{LockBackend, TokioMutex};
+use crate::map::{Db, DefaultHashBuilder, ShardedMap};
+use crate::version::Versioned;
+
+
+/*
+
+    the background reconciler of a sharded map, it takes the updates from
+    the mpsc job queue of the map until the cancellation token gets cancelled,
+    all the updates that are already in the queue are taken at once as a
+    single batch (up to the batch size of the map) so a burst of writes ends
+    up in a single pass, only the shards that have been written are looked
+    at and only their stray keys are moved home, then the merged views get
+    broadcasted to all the subscribers of the map, the whole pool is only
+    refreshed every refresh interval of the map if there is one, once
+    cancelled the pending updates of the queue are drained and reconciled
+    one last time before exiting, the reconciler only holds a weak handle
+    of the map so once every handle of the map is dropped the queue gets
+    closed and the reconciler stops on its own.
+
+*/
+pub struct Reconciler<K, V, S = DefaultHashBuilder, B = TokioMutex> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    map: Weak<ShardedMap<K, V, S, B>>,
+    updates: mpsc::Receiver<usize>,
+}
+
+impl<K, V, S, B> Reconciler<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+
+    /// there is only one reconciler per map since it owns the receiving half of the queue
+    pub fn new(map: Arc<ShardedMap<K, V, S, B>>) -> Result<Self>{
+        let updates = map.take_updates_receiver().ok_or(S3Error::ReconcilerAlreadySpawned)?;
+        Ok(Self{map: Arc::downgrade(&map), updates})
+    }
+
+    pub fn spawn(self, token: CancellationToken) -> JoinHandle<()>{
+        tokio::spawn(self.run(token))
+    }
+
+    pub async fn run(mut self, token: CancellationToken){
+        let Some(map) = self.map.upgrade() else{
+            return;
+        };
+        let batch_size = map.batch_size();
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut refresh = map.refresh_interval().map(|period| {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+        drop(map);
+        loop{
+            tokio::select!{
+                biased;
+                _ = token.cancelled() => {
+                    tracing::debug!("the reconciler has been cancelled, draining the pending updates");
+                    self.drain(&mut batch).await;
+                    break;
+                },
+                received = self.updates.recv_many(&mut batch, batch_size) => {
+                    if received == 0{
+                        tracing::debug!("all the senders of the updates queue are gone, stopping the reconciler");
+                        break;
+                    }
+                    self.apply(&mut batch).await;
+                },
+                _ = tick(&mut refresh) => self.refresh().await,
+            }
+        }
+    }
+
+    async fn drain(&mut self, batch: &mut Vec<usize>){
+        while let Ok(shard) = self.updates.try_recv(){
+            batch.push(shard);
+        }
+        if !batch.is_empty(){
+            self.apply(batch).await;
+        }
+    }
+
+    #[tracing::instrument(level = "debug", name = "merge", skip_all, fields(updates = batch.len()))]
+    async fn apply(&mut self, batch: &mut Vec<usize>){
+        batch.clear();
+        //// the map is gone, the queue is about to be closed
+        let Some(map) = self.map.upgrade() else{
+            return;
+        };
+        match map.reconcile_dirty().await{
+            Ok(_) => {
+                //// nobody to merge the views for
+                if map.subscriber_count() > 0{
+                    publish(&map, map.view_merged());
+                }
+            },
+            //// the failed shards are still dirty so they'll be reconciled on the next batch
+            Err(e) => tracing::warn!(error = %e, "failed to reconcile the dirty shards"),
+        }
+        if let Some((contention, parts)) = map.auto_split(){
+            if let Err(e) = map.split_hot_shard(contention, parts).await{
+                tracing::warn!(error = %e, "failed to split the hot shard");
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn refresh(&self){
+        let Some(map) = self.map.upgrade() else{
+            return;
+        };
+        match map.refresh().await{
+            Ok(merged) => publish(&map, merged),
+            Err(e) => tracing::warn!(error = %e, "failed to refresh the shards"),
+        }
+    }
+
+}
+
+fn publish<K, V, S, B>(map: &ShardedMap<K, V, S, B>, merged: Db<K, Versioned<V>>) where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+    let entries = merged.len();
+    let subscribers = map.publish(merged);
+    tracing::debug!(entries, subscribers, "published the merged shards");
+}
+
+/// the next tick of the periodic refresh, never if there is none
+async fn tick(refresh: &mut Option<tokio::time::Interval>){
+    match refresh{
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => std::future::pending().await,
+    }
+}
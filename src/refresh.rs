@@ -0,0 +1,58 @@
+
+
+use std::hash::{BuildHasher, Hash};
+use tokio::sync::SemaphorePermit;
+use crate::error::{Result, S3Error};
+use crate::lock::LockBackend;
+use crate::map::{Db, ShardedMap};
+use crate::version::Versioned;
+
+
+/// the admission of a writer into the pool, the refresh phase waits for all of them to be dropped
+pub type WritePermit<'a> = SemaphorePermit<'a>;
+
+
+/*
+
+    we have to update the whole shards inside the pool at the end of each
+    mutex free process which is something that is taken care of by the write
+    semaphore of the map, every writer holds one permit while it's working on
+    the pool and the refresh phase takes all the permits at once so:
+
+        - it waits for all the in-flight writers to release their permits
+        - no new writer gets admitted while the reconciled data is being applied
+          to every shard since the semaphore is fair and the refresh is queued
+          before them
+        - the writers are readmitted once all the shards have been updated
+
+    so a writer never observes a pool that is half updated, the reconciler
+    moves the stray keys of the dirty shards home the same way.
+
+*/
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /// admits the caller as a writer of the pool until the permit gets dropped
+    pub async fn write_permit(&self) -> Result<WritePermit<'_>>{
+        self.admission().acquire().await.map_err(|_| S3Error::ChannelClosed)
+    }
+
+    /// merges the shards and applies the merged data to every shard while no writer is admitted
+    pub async fn refresh(&self) -> Result<Db<K, Versioned<V>>>{
+        let _all = self.admit_none().await?;
+        self.reconcile().await
+    }
+
+    /// takes every permit so no writer is admitted until it's dropped, taken before the resharding lock
+    pub(crate) async fn admit_none(&self) -> Result<WritePermit<'_>>{
+        self.admission()
+            .acquire_many(self.max_writers())
+            .await
+            .map_err(|_| S3Error::ChannelClosed)
+    }
+
+}
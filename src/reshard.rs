@@ -0,0 +1,217 @@
+
+
+
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use crate::error::{Result, S3Error};
+use crate::lock::{LockBackend, ShardLock};
+use crate::map::{ShardDb, ShardedMap};
+use crate::placement::Router;
+use crate::reconcile;
+use crate::sync::AtomicBool;
+use crate::version::Versioned;
+
+
+/*
+
+    the routing of the keys to their home shard, while the pool is being
+    resharded a key lives inside its old home until the old home has been
+    migrated, then it lives inside its new home, every old shard has a flag
+    that gets set once all of its keys have been moved to their new home so
+    the keys are never routed to a shard they're not in.
+
+*/
+pub(crate) struct Layout{
+    old: Option<Arc<Router>>,
+    new: Arc<Router>,
+    migrated: Vec<AtomicBool>,
+}
+
+impl Layout{
+
+    pub(crate) fn stable(router: Arc<Router>) -> Self{
+        Self{old: None, new: router, migrated: Vec::new()}
+    }
+
+    fn migrating(old: Arc<Router>, new: Arc<Router>) -> Self{
+        let migrated = (0..old.shard_count()).map(|_| AtomicBool::new(false)).collect();
+        Self{old: Some(old), new, migrated}
+    }
+
+    pub(crate) fn route(&self, hash: u64) -> usize{
+        if let Some(old) = &self.old{
+            let old = old.route(hash);
+            if !self.migrated[old].load(Ordering::Acquire){
+                return old;
+            }
+        }
+        self.new_home(hash)
+    }
+
+    /// the home of the key once the resharding is done
+    fn new_home(&self, hash: u64) -> usize{
+        self.new.route(hash)
+    }
+
+    pub(crate) fn shard_count(&self) -> usize{
+        self.new.shard_count()
+    }
+
+    /// shards that will still be there once the resharding is done
+    pub(crate) fn active_shards(&self) -> usize{
+        let old = self.old.as_ref().map_or(usize::MAX, |old| old.shard_count());
+        old.min(self.new.shard_count())
+    }
+
+    /// the router of the keys once the resharding is done
+    fn router(&self) -> &Arc<Router>{
+        &self.new
+    }
+
+}
+
+
+/// what a resharding has done to the pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReshardReport{
+    pub from: usize,
+    pub to: usize,
+    /// keys that have been moved to a new home
+    pub moved: usize,
+}
+
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /*
+
+        changes the number of shards of the pool while the readers and the
+        writers keep working on it, the new shards are allocated up front and
+        then the old shards are migrated one by one, a migrating shard stays
+        locked until all of its keys have been moved to their new home so no
+        one can see a key that is in neither of them, the shards that are
+        retired by shrinking the pool are kept around empty since they may
+        still be borrowed, only one resharding or reconciliation runs at a time.
+
+    */
+    pub async fn reshard(&self, shard_count: usize) -> Result<ReshardReport>{
+        if shard_count == 0{
+            return Err(S3Error::InvalidReshard("a sharded map needs at least one shard".to_string()));
+        }
+        let _resharding = self.resharding().lock().await;
+        if self.shard_count() == shard_count{
+            return Ok(ReshardReport{from: shard_count, to: shard_count, moved: 0});
+        }
+        self.relayout(Arc::new(self.placement().router(shard_count))).await
+    }
+
+    /*
+
+        splits a single shard into `parts` shards so the keys of a hot shard
+        get spread over new sub shards while the other shards are left as
+        they are, the sub shards are appended to the pool, this is the same
+        migration as the resharding so reads and writes keep going.
+
+    */
+    pub async fn split_shard(&self, shard: usize, parts: usize) -> Result<ReshardReport>{
+        if parts < 2{
+            return Err(S3Error::InvalidReshard(format!("a shard must be split into at least two parts, not {}", parts)));
+        }
+        let _resharding = self.resharding().lock().await;
+        if shard >= self.shard_count(){
+            return Err(S3Error::InvalidReshard(format!("shard {} is not in the pool of {} shards", shard, self.shard_count())));
+        }
+        let router = self.layout().load().router().split(shard, parts);
+        self.relayout(Arc::new(router)).await
+    }
+
+    /// moves the keys from the current layout to the router, the caller must hold the resharding lock
+    #[tracing::instrument(level = "info", skip_all, fields(from = self.shard_count(), to = router.shard_count()))]
+    async fn relayout(&self, router: Arc<Router>) -> Result<ReshardReport>{
+        let old = self.layout().load().router().clone();
+        let mut report = ReshardReport{from: old.shard_count(), to: router.shard_count(), moved: 0};
+        while self.shards().len() < router.shard_count(){
+            self.push_shard();
+        }
+
+        self.layout().store(Arc::new(Layout::migrating(old, router.clone())));
+        for shard in 0..self.shards().len(){
+            self.migrate(shard, &mut report).await?;
+        }
+        self.layout().store(Arc::new(Layout::stable(router)));
+
+        self.metrics().record_reshard(report.moved);
+        tracing::info!(moved = report.moved, "the keys have been moved to their new homes");
+        Ok(report)
+    }
+
+    /*
+
+        moves the keys of the shard to their new home, the keys that are
+        already there win on the same version, the shard stays locked until
+        its keys are inside their new home but we never wait on another lock
+        while holding it, so if a new home is busy the shard is released and
+        the whole move is tried again.
+
+    */
+    async fn migrate(&self, shard: usize, report: &mut ReshardReport) -> Result<()>{
+        let layout = self.layout().load_full();
+        loop{
+            let migrated = {
+                let mut source = self.lock_shard(shard).await?;
+                self.try_migrate(shard, &layout, &mut source)?
+            };
+            if let Some(moved) = migrated{
+                report.moved += moved;
+                return Ok(());
+            }
+            tracing::trace!(shard, "a new home of the shard is busy, trying the migration again");
+            B::backoff().await;
+        }
+    }
+
+    fn try_migrate(&self, shard: usize, layout: &Layout, source: &mut ShardDb<K, V>) -> Result<Option<usize>>{
+        let new_home = |key: &K| layout.new_home(self.key_hash(key));
+        let mut moving = BTreeMap::<usize, Vec<(K, Versioned<V>)>>::new();
+        for (key, value) in source.extract_if(|key, _| new_home(key) != shard){
+            moving.entry(new_home(&key)).or_default().push((key, value));
+        }
+
+        let mut dests = Vec::with_capacity(moving.len());
+        for &home in moving.keys(){
+            match self.shard(home).try_write().map_err(|_| S3Error::ShardPoisoned{shard: home})?{
+                Some(dest) => dests.push(dest),
+                None => {
+                    //// put them back, we'll try again once the new home is free
+                    source.extend(moving.into_values().flatten());
+                    return Ok(None);
+                }
+            }
+        }
+
+        let mut moved = 0;
+        for ((home, entries), mut dest) in moving.into_iter().zip(dests){
+            moved += entries.len();
+            for (key, value) in entries{
+                reconcile::keep_latest(&mut dest, key, value);
+            }
+            self.store_view(home, &dest);
+            self.sync_replicas(&dest, |key| new_home(key) == home);
+        }
+
+        //// the old shard is the home of its keys until here
+        if let Some(migrated) = layout.migrated.get(shard){
+            migrated.store(true, Ordering::Release);
+        }
+        self.store_view(shard, source);
+        Ok(Some(moved))
+    }
+
+}
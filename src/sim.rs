@@ -0,0 +1,398 @@
+
+
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use crate::lifecycle::panic_message;
+use crate::placement::mix;
+
+
+/// the virtual clock moves forward by up to this many nanoseconds on every step
+const MAX_JITTER: u64 = 1_000;
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local!{
+    static CURRENT: RefCell<Option<Rc<Executor>>> = const { RefCell::new(None) };
+}
+
+
+/*
+
+    a deterministic single threaded executor for simulating the map, every
+    task that is ready to run is put in a set and on each step one of them
+    is picked by the seeded rng, so the seed alone decides the interleaving
+    of the tasks and thus the order the messages of the channels are sent
+    and received in, the time is virtual too, it moves forward by a seeded
+    jitter on every step and jumps to the next timer once nothing is ready,
+    the same seed always replays the same run with the same schedule.
+
+    only the async lock backends (tokio mutex and rwlock) can be simulated
+    since a blocking lock would block the only thread, and the tasks must
+    not use the tokio runtime (tokio::spawn or tokio::time), they spawn and
+    sleep with the functions of this module instead.
+
+*/
+pub struct Simulation{
+    seed: u64,
+    max_steps: u64,
+}
+
+impl Simulation{
+
+    pub fn new(seed: u64) -> Self{
+        Self{seed, max_steps: 10_000_000}
+    }
+
+    /// the run fails with StepLimit after this many steps, something is probably spinning
+    pub fn max_steps(mut self, max_steps: u64) -> Self{
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// runs the future and all the tasks it spawns until the future is done
+    pub fn run<F>(self, main: F) -> Result<(F::Output, SimReport), SimError> where
+        F: Future + 'static
+    {
+        let executor = Rc::new(Executor::new(self.seed));
+        let _current = Current::enter(executor.clone());
+        let main = executor.spawn(main);
+
+        loop{
+            if let Some(output) = main.take(){
+                return Ok((output, executor.report()));
+            }
+            let steps = executor.steps.get();
+            if steps >= self.max_steps{
+                return Err(SimError::StepLimit{seed: self.seed, steps});
+            }
+            match executor.next_ready(){
+                Some(task) => executor.step(task)?,
+                None if executor.advance_to_next_timer() => {},
+                None => return Err(SimError::Deadlock{seed: self.seed, steps, pending: executor.pending()}),
+            }
+        }
+    }
+
+}
+
+
+/// what a finished simulation looks like, two runs with the same schedule have the same fingerprint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SimReport{
+    pub seed: u64,
+    /// number of times a task has been polled
+    pub steps: u64,
+    /// the virtual time at the end of the run
+    pub elapsed: Duration,
+    /// a hash of the tasks in the order they have been polled
+    pub fingerprint: u64,
+}
+
+/// a simulation that went wrong, rerunning its seed reproduces it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SimError{
+    /// no task can make progress and no timer is pending
+    Deadlock{seed: u64, steps: u64, pending: usize},
+    StepLimit{seed: u64, steps: u64},
+    Panicked{seed: u64, step: u64, task: usize, message: String},
+}
+
+impl SimError{
+
+    pub fn seed(&self) -> u64{
+        match self{
+            SimError::Deadlock{seed, ..} | SimError::StepLimit{seed, ..} | SimError::Panicked{seed, ..} => *seed,
+        }
+    }
+
+}
+
+impl fmt::Display for SimError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            SimError::Deadlock{seed, steps, pending} => write!(f, "seed {} deadlocked after {} steps with {} pending tasks", seed, steps, pending),
+            SimError::StepLimit{seed, steps} => write!(f, "seed {} didn't finish within {} steps", seed, steps),
+            SimError::Panicked{seed, step, task, message} => write!(f, "seed {}: task {} panicked at step {}: {}", seed, task, step, message),
+        }
+    }
+}
+
+impl std::error::Error for SimError{}
+
+
+/// spawns a task on the current simulation, panics outside of a simulation
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output> where
+    F: Future + 'static
+{
+    current().spawn(future)
+}
+
+/// the virtual time since the start of the current simulation
+pub fn now() -> Duration{
+    Duration::from_nanos(current().now.get())
+}
+
+/// waits for the virtual time to move forward by `duration`
+pub fn sleep(duration: Duration) -> Sleep{
+    Sleep{duration, deadline: None}
+}
+
+/// a number drawn from the rng of the current simulation, so it's part of the replayed run
+pub fn random<T>() -> T where
+    rand::distributions::Standard: rand::distributions::Distribution<T>
+{
+    current().rng.borrow_mut().gen()
+}
+
+fn current() -> Rc<Executor>{
+    CURRENT.with(|current| current.borrow().clone()).expect("not inside a simulation")
+}
+
+
+/// the output of a spawned task
+pub struct JoinHandle<T>{
+    slot: Rc<RefCell<Slot<T>>>,
+}
+
+struct Slot<T>{
+    output: Option<T>,
+    waiter: Option<Waker>,
+}
+
+impl<T> JoinHandle<T>{
+
+    fn take(&self) -> Option<T>{
+        self.slot.borrow_mut().output.take()
+    }
+
+}
+
+impl<T> Future for JoinHandle<T>{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T>{
+        let mut slot = self.slot.borrow_mut();
+        match slot.output.take(){
+            Some(output) => Poll::Ready(output),
+            None => {
+                slot.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+
+/// the future of sleep()
+pub struct Sleep{
+    duration: Duration,
+    deadline: Option<u64>,
+}
+
+impl Future for Sleep{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()>{
+        let executor = current();
+        let now = executor.now.get();
+        let duration = self.duration.as_nanos() as u64;
+        let deadline = *self.deadline.get_or_insert(now + duration);
+        if now >= deadline{
+            return Poll::Ready(());
+        }
+        executor.add_timer(deadline, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+
+/// the tasks wake themselves up by putting their id in the ready set
+struct TaskWaker{
+    task: usize,
+    ready: Arc<Mutex<BTreeSet<usize>>>,
+}
+
+impl Wake for TaskWaker{
+    fn wake(self: Arc<Self>){
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>){
+        self.ready.lock().unwrap_or_else(|e| e.into_inner()).insert(self.task);
+    }
+}
+
+
+struct Executor{
+    seed: u64,
+    rng: RefCell<ChaCha12Rng>,
+    /// a task is taken out of its slot while it's being polled so it can spawn other tasks
+    tasks: RefCell<Vec<Option<Task>>>,
+    ready: Arc<Mutex<BTreeSet<usize>>>,
+    /// keyed by the deadline and the order they've been added in, so two timers never share a key
+    timers: RefCell<BTreeMap<(u64, u64), Waker>>,
+    next_timer: Cell<u64>,
+    now: Cell<u64>,
+    steps: Cell<u64>,
+    fingerprint: Cell<u64>,
+}
+
+impl Executor{
+
+    fn new(seed: u64) -> Self{
+        Self{
+            seed,
+            rng: RefCell::new(ChaCha12Rng::seed_from_u64(seed)),
+            tasks: RefCell::new(Vec::new()),
+            ready: Arc::new(Mutex::new(BTreeSet::new())),
+            timers: RefCell::new(BTreeMap::new()),
+            next_timer: Cell::new(0),
+            now: Cell::new(0),
+            steps: Cell::new(0),
+            fingerprint: Cell::new(mix(seed)),
+        }
+    }
+
+    fn ready(&self) -> std::sync::MutexGuard<'_, BTreeSet<usize>>{
+        self.ready.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output> where
+        F: Future + 'static
+    {
+        let slot = Rc::new(RefCell::new(Slot{output: None, waiter: None}));
+        let handle = JoinHandle{slot: slot.clone()};
+        let task = Box::pin(async move{
+            let output = future.await;
+            let waiter = {
+                let mut slot = slot.borrow_mut();
+                slot.output = Some(output);
+                slot.waiter.take()
+            };
+            if let Some(waiter) = waiter{
+                waiter.wake();
+            }
+        });
+        let mut tasks = self.tasks.borrow_mut();
+        tasks.push(Some(task));
+        self.ready().insert(tasks.len() - 1);
+        handle
+    }
+
+    /// one of the ready tasks picked by the rng
+    fn next_ready(&self) -> Option<usize>{
+        let mut ready = self.ready();
+        if ready.is_empty(){
+            return None;
+        }
+        let nth = self.rng.borrow_mut().gen_range(0..ready.len());
+        let task = *ready.iter().nth(nth)?;
+        ready.remove(&task);
+        Some(task)
+    }
+
+    fn step(&self, task: usize) -> Result<(), SimError>{
+        let step = self.steps.get() + 1;
+        self.steps.set(step);
+        self.fingerprint.set(mix(self.fingerprint.get() ^ task as u64));
+        let jitter = self.rng.borrow_mut().gen_range(1..=MAX_JITTER);
+        self.now.set(self.now.get() + jitter);
+        self.fire_timers();
+
+        //// a finished task may still be woken by a stale waker
+        let Some(mut future) = self.tasks.borrow_mut()[task].take() else{
+            return Ok(());
+        };
+        let waker = Waker::from(Arc::new(TaskWaker{task, ready: self.ready.clone()}));
+        let mut cx = Context::from_waker(&waker);
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))){
+            Ok(Poll::Pending) => self.tasks.borrow_mut()[task] = Some(future),
+            Ok(Poll::Ready(())) => {},
+            Err(payload) => return Err(SimError::Panicked{seed: self.seed, step, task, message: panic_message(payload)}),
+        }
+        Ok(())
+    }
+
+    fn add_timer(&self, deadline: u64, waker: Waker){
+        let seq = self.next_timer.get();
+        self.next_timer.set(seq + 1);
+        self.timers.borrow_mut().insert((deadline, seq), waker);
+    }
+
+    fn fire_timers(&self){
+        let now = self.now.get();
+        let mut timers = self.timers.borrow_mut();
+        while let Some(entry) = timers.first_entry(){
+            if entry.key().0 > now{
+                break;
+            }
+            entry.remove().wake();
+        }
+    }
+
+    /// jumps to the first timer once nothing is ready, false if there is no timer at all
+    fn advance_to_next_timer(&self) -> bool{
+        let deadline = match self.timers.borrow().first_key_value(){
+            Some(((deadline, _), _)) => *deadline,
+            None => return false,
+        };
+        self.now.set(self.now.get().max(deadline));
+        self.fire_timers();
+        true
+    }
+
+    fn pending(&self) -> usize{
+        self.tasks.borrow().iter().filter(|task| task.is_some()).count()
+    }
+
+    fn report(&self) -> SimReport{
+        SimReport{
+            seed: self.seed,
+            steps: self.steps.get(),
+            elapsed: Duration::from_nanos(self.now.get()),
+            fingerprint: self.fingerprint.get(),
+        }
+    }
+
+}
+
+
+/// makes the executor the current one until it gets dropped
+struct Current;
+
+impl Current{
+
+    fn enter(executor: Rc<Executor>) -> Self{
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            assert!(current.is_none(), "a simulation can't run inside another one");
+            *current = Some(executor);
+        });
+        Current
+    }
+
+}
+
+impl Drop for Current{
+    fn drop(&mut self){
+        //// the tasks that never finished are dropped while the executor is still current
+        let executor = CURRENT.with(|current| current.borrow_mut().take());
+        if let Some(executor) = executor{
+            let tasks = std::mem::take(&mut *executor.tasks.borrow_mut());
+            CURRENT.with(|current| *current.borrow_mut() = Some(executor.clone()));
+            drop(tasks);
+            CURRENT.with(|current| current.borrow_mut().take());
+        }
+    }
+}
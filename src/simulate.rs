@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::Args;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use s3::sim::{self, SimError, SimReport, Simulation};
+use s3::{CancellationToken, LockBackend, Reconciler, S3Error, ShardedMap, ShardedMapBuilder, Subscriber};
+use crate::config::{Backend, Config, ConfigError, Hasher};
+
+
+/// the map that is simulated, every value is tagged with its key so a misplaced value can be spotted
+pub type SimMap<S, B> = ShardedMap<u64, u64, S, B>;
+
+
+#[derive(Clone, Debug, Args)]
+pub struct SimArgs{
+    /// number of consecutive seeds to simulate, starting at --seed
+    #[arg(long, default_value_t = 1)]
+    pub seeds: u64,
+
+    /// number of tasks that write to the map concurrently
+    #[arg(long, default_value_t = 4)]
+    pub writers: u64,
+
+    /// number of operations of every writer
+    #[arg(long, default_value_t = 200)]
+    pub operations: u64,
+
+    /// number of keys owned by every writer
+    #[arg(long, default_value_t = 16)]
+    pub keys: u64,
+
+    /// number of times the map gets resharded while the writers are running
+    #[arg(long, default_value_t = 2)]
+    pub reshards: u64,
+
+    /// a seed fails once its run takes more steps than this
+    #[arg(long, default_value_t = 1_000_000)]
+    pub max_steps: u64,
+}
+
+impl SimArgs{
+
+    /// the simulation can only replay what is driven by the seed
+    pub fn validate(&self, config: &Config) -> Result<(), ConfigError>{
+        let invalid = |field, reason| Err(ConfigError::Invalid{field, reason});
+        if self.seeds == 0{
+            return invalid("seeds", "at least one seed must be simulated");
+        }
+        if self.writers == 0{
+            return invalid("writers", "at least one writer must run");
+        }
+        if self.keys == 0{
+            return invalid("keys", "every writer needs at least one key");
+        }
+        if self.writers.saturating_mul(self.keys) > u64::from(u32::MAX) || self.operations > u64::from(u32::MAX){
+            return invalid("operations", "the keys and the operations of the writers must fit in 32 bits");
+        }
+        if config.hasher != Hasher::Fx{
+            return invalid("hasher", "the simulation needs the fx hasher, the sip one is randomly keyed");
+        }
+        if !matches!(config.backend, Backend::TokioMutex | Backend::TokioRwlock){
+            return invalid("backend", "the simulation needs an async lock backend, a blocking one would block the executor");
+        }
+        Ok(())
+    }
+
+}
+
+
+/*
+
+    runs the seeds one after the other on the deterministic executor of
+    the lib, every run has its writers, a resharder, a subscriber and the
+    reconciler racing on a fresh map, the writers own disjoint keys so each
+    of them keeps a model of its own keys and checks every answer of the
+    map against it, at the end the map must hold exactly what the models
+    hold, a failing seed is printed so it can be replayed with --seed.
+
+*/
+pub fn run<S, B>(args: &SimArgs, first_seed: u64, builder: ShardedMapBuilder<S, B>) -> Result<(), SimError> where
+    S: BuildHasher + Clone + Send + Sync + 'static,
+    B: LockBackend + Clone
+{
+    let mut failed = None;
+    for seed in first_seed..first_seed.saturating_add(args.seeds){
+        match simulate(args, seed, builder.clone()){
+            Ok(report) => println!(
+                "seed {} passed in {} steps and {:.2?} of virtual time, schedule {:016x}",
+                report.seed, report.steps, report.elapsed, report.fingerprint
+            ),
+            Err(e) => {
+                println!("{}, replay it with --seed {} sim --seeds 1", e, e.seed());
+                failed.get_or_insert(e);
+            }
+        }
+    }
+    match failed{
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn simulate<S, B>(args: &SimArgs, seed: u64, builder: ShardedMapBuilder<S, B>) -> Result<SimReport, SimError> where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+    let (args, map) = (args.clone(), Arc::new(builder.build()));
+    //// the executor must not run on a thread of the tokio runtime
+    std::thread::scope(|scope| {
+        scope.spawn(move || Simulation::new(seed).max_steps(args.max_steps).run(workload(args, map)))
+            .join()
+            .expect("the simulation has panicked outside of its tasks")
+    }).map(|(_, report)| report)
+}
+
+async fn workload<S, B>(args: SimArgs, map: Arc<SimMap<S, B>>) where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+    let token = CancellationToken::new();
+    let reconciler = Reconciler::new(map.clone()).expect("the map has just been built");
+    let reconciler = sim::spawn(reconciler.run(token.clone()));
+    let subscriber = sim::spawn(subscribe(map.subscribe(), token.clone()));
+
+    let writers = (0..args.writers)
+        .map(|writer| sim::spawn(write(map.clone(), args.clone(), writer, ChaCha12Rng::seed_from_u64(sim::random()))))
+        .collect::<Vec<_>>();
+    let resharder = sim::spawn(reshard(map.clone(), args.clone(), ChaCha12Rng::seed_from_u64(sim::random())));
+
+    let mut model = HashMap::new();
+    for writer in writers{
+        model.extend(writer.await);
+    }
+    resharder.await;
+    token.cancel();
+    reconciler.await;
+    subscriber.await;
+
+    let stored = map.snapshot().await.expect("the snapshot has failed")
+        .into_iter()
+        .map(|(key, versioned)| (key, versioned.value))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(stored, model, "the map doesn't hold what the writers have written");
+}
+
+/// the writer of the keys `writer`, `writer + writers`, ... which returns the model of its keys
+async fn write<S, B>(map: Arc<SimMap<S, B>>, args: SimArgs, writer: u64, mut rng: ChaCha12Rng) -> HashMap<u64, u64> where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+    let mut model = HashMap::new();
+    for op in 0..args.operations{
+        let key = rng.gen_range(0..args.keys) * args.writers + writer;
+        match rng.gen_range(0..10){
+            0..=5 => {
+                let value = op << 32 | key;
+                let old = map.insert(key, value).await.expect("the insert has failed");
+                assert_eq!(old, model.insert(key, value), "insert of key {} by writer {} returned the wrong old value", key, writer);
+            },
+            6..=7 => {
+                let removed = found(map.remove(&key).await);
+                assert_eq!(removed, model.remove(&key), "remove of key {} by writer {} returned the wrong value", key, writer);
+            },
+            _ => {
+                let value = found(map.get(&key).await);
+                assert_eq!(value.as_ref(), model.get(&key), "get of key {} by writer {} returned the wrong value", key, writer);
+            },
+        }
+        if rng.gen_bool(0.5){
+            tokio::task::yield_now().await;
+        } else{
+            sim::sleep(Duration::from_micros(rng.gen_range(0..50))).await;
+        }
+    }
+    model
+}
+
+/// grows and shrinks the pool while the writers are running
+async fn reshard<S, B>(map: Arc<SimMap<S, B>>, args: SimArgs, mut rng: ChaCha12Rng) where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+    let shards = map.shard_count();
+    for _ in 0..args.reshards{
+        sim::sleep(Duration::from_micros(rng.gen_range(0..args.operations * 25))).await;
+        map.reshard(rng.gen_range(1..=shards * 2)).await.expect("the resharding has failed");
+    }
+}
+
+/// every published value must be one that has been written for its key
+async fn subscribe<S, B>(mut subscriber: Subscriber<u64, u64, S, B>, token: CancellationToken) where
+    S: BuildHasher + Send + Sync + 'static,
+    B: LockBackend
+{
+    loop{
+        //// biased since tokio picks the branches of select randomly
+        tokio::select!{
+            biased;
+            _ = token.cancelled() => break,
+            published = subscriber.recv() => {
+                let published = published.expect("the map is gone");
+                for (key, versioned) in published.iter(){
+                    assert_eq!(versioned.value & u64::from(u32::MAX), *key, "the value of key {} has been published for another key", key);
+                }
+            }
+        }
+    }
+}
+
+fn found(result: Result<u64, S3Error>) -> Option<u64>{
+    match result{
+        Ok(value) => Some(value),
+        Err(S3Error::KeyNotFound) => None,
+        Err(e) => panic!("the map has failed: {}", e),
+    }
+}
@@ -0,0 +1,282 @@
+
+
+
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use hdrhistogram::Histogram;
+use thread_local::ThreadLocal;
+use crate::lock::LockBackend;
+use crate::map::ShardedMap;
+
+
+/// the kind of an operation that is counted for the shard it has touched
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Op{
+    Get,
+    Insert,
+    Remove,
+}
+
+
+/*
+
+    the counters and the latency histograms of a single shard, the waits
+    are measured from the first attempt to lock the shard until the lock is
+    acquired and the holds from there until the guard gets dropped, both in
+    nanoseconds, a try_lock failure is any time the shard was already locked
+    by someone else whether the caller has waited for it or moved on to
+    another shard, every thread records into its own histograms so the
+    lock of a shard is never slowed down by the stats of another thread,
+    they're only merged together once the stats are taken.
+
+*/
+pub(crate) struct LockStats{
+    acquisitions: AtomicU64,
+    skips: AtomicU64,
+    contended: AtomicU64,
+    gets: AtomicU64,
+    inserts: AtomicU64,
+    removes: AtomicU64,
+    wait: Latencies,
+    hold: Latencies,
+}
+
+impl Default for LockStats{
+    fn default() -> Self{
+        Self{
+            acquisitions: AtomicU64::new(0),
+            skips: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            removes: AtomicU64::new(0),
+            wait: Latencies::default(),
+            hold: Latencies::default(),
+        }
+    }
+}
+
+impl LockStats{
+
+    /// a write lock of the shard that has been acquired after `wait`
+    pub(crate) fn record(&self, contended: bool, wait: Duration){
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if contended{
+            self.contended.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wait.record(wait);
+    }
+
+    /// a read lock of the shard that has been acquired after `wait`
+    pub(crate) fn record_read(&self, wait: Duration){
+        self.wait.record(wait);
+    }
+
+    /// a shard that has been skipped by acquire_any_shard() without being locked
+    pub(crate) fn record_skip(&self){
+        self.skips.fetch_add(1, Ordering::Relaxed);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_hold(&self, hold: Duration){
+        self.hold.record(hold);
+    }
+
+    pub(crate) fn record_op(&self, op: Op){
+        let counter = match op{
+            Op::Get => &self.gets,
+            Op::Insert => &self.inserts,
+            Op::Remove => &self.removes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn acquisitions(&self) -> u64{
+        self.acquisitions.load(Ordering::Relaxed)
+    }
+
+    /// every try of locking the shard, the skipped ones included
+    pub(crate) fn attempts(&self) -> u64{
+        self.acquisitions() + self.skips.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn contended(&self) -> u64{
+        self.contended.load(Ordering::Relaxed)
+    }
+
+    /// starts the contention over, the latencies and the operations are kept
+    pub(crate) fn reset_contention(&self){
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.skips.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, shard: usize) -> ShardStats{
+        ShardStats{
+            shard,
+            acquisitions: self.acquisitions(),
+            attempts: self.attempts(),
+            try_lock_failures: self.contended(),
+            gets: self.gets.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            wait: self.wait.merged(),
+            hold: self.hold.merged(),
+        }
+    }
+
+}
+
+fn histogram() -> Histogram<u64>{
+    Histogram::new(3).expect("3 significant figures are allowed")
+}
+
+/// a latency histogram per thread, the mutex of a thread is only shared with the one taking the stats
+#[derive(Default)]
+struct Latencies(ThreadLocal<Mutex<Histogram<u64>>>);
+
+impl Latencies{
+
+    /// the histograms grow to fit the slowest latency, saturating_record() would clamp it instead
+    fn record(&self, latency: Duration){
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let local = self.0.get_or(|| Mutex::new(histogram()));
+        let _ = local.lock().unwrap_or_else(|e| e.into_inner()).record(nanos);
+    }
+
+    fn merged(&self) -> Histogram<u64>{
+        let mut merged = histogram();
+        for local in self.0.iter(){
+            //// both of them auto resize so adding never fails
+            let _ = merged.add(&*local.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+        merged
+    }
+
+}
+
+
+/// a lock guard of a shard that records how long it has been held once it gets dropped
+pub(crate) struct Timed<'a, G>{
+    gaurd: G,
+    stats: &'a LockStats,
+    since: Instant,
+}
+
+impl<'a, G> Timed<'a, G>{
+    pub(crate) fn new(gaurd: G, stats: &'a LockStats) -> Self{
+        Self{gaurd, stats, since: Instant::now()}
+    }
+}
+
+impl<G: Deref> Deref for Timed<'_, G>{
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target{
+        &self.gaurd
+    }
+}
+
+impl<G: DerefMut> DerefMut for Timed<'_, G>{
+    fn deref_mut(&mut self) -> &mut Self::Target{
+        &mut self.gaurd
+    }
+}
+
+impl<G> Drop for Timed<'_, G>{
+    fn drop(&mut self){
+        self.stats.record_hold(self.since.elapsed());
+    }
+}
+
+
+/// the counters and the latencies of a shard, the latencies are in nanoseconds
+#[derive(Clone, Debug)]
+pub struct ShardStats{
+    pub shard: usize,
+    /// number of times the shard has been write locked
+    pub acquisitions: u64,
+    /// number of times someone has tried to lock the shard, the skipped ones included
+    pub attempts: u64,
+    /// number of times the shard was already locked when someone tried to lock it
+    pub try_lock_failures: u64,
+    pub gets: u64,
+    pub inserts: u64,
+    pub removes: u64,
+    /// how long it took to acquire the lock of the shard
+    pub wait: Histogram<u64>,
+    /// how long the lock of the shard has been held
+    pub hold: Histogram<u64>,
+}
+
+impl ShardStats{
+
+    pub fn operations(&self) -> u64{
+        self.gets + self.inserts + self.removes
+    }
+
+    /// the part of the lock attempts that found the shard already locked
+    pub fn try_lock_failure_rate(&self) -> f64{
+        if self.attempts == 0{
+            return 0.0;
+        }
+        self.try_lock_failures as f64 / self.attempts as f64
+    }
+
+}
+
+
+/// the stats of all the shards of the map
+#[derive(Clone, Debug)]
+pub struct Stats{
+    pub shards: Vec<ShardStats>,
+}
+
+impl Stats{
+
+    pub fn operations(&self) -> u64{
+        self.shards.iter().map(ShardStats::operations).sum()
+    }
+
+    pub fn try_lock_failures(&self) -> u64{
+        self.shards.iter().map(|shard| shard.try_lock_failures).sum()
+    }
+
+    /// the lock waits of all the shards together
+    pub fn wait(&self) -> Histogram<u64>{
+        merged(self.shards.iter().map(|shard| &shard.wait))
+    }
+
+    /// the lock holds of all the shards together
+    pub fn hold(&self) -> Histogram<u64>{
+        merged(self.shards.iter().map(|shard| &shard.hold))
+    }
+
+}
+
+fn merged<'a>(histograms: impl Iterator<Item = &'a Histogram<u64>>) -> Histogram<u64>{
+    let mut merged = histogram();
+    for histogram in histograms{
+        //// both of them auto resize so adding never fails
+        let _ = merged.add(histogram);
+    }
+    merged
+}
+
+
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /// the counters and the latencies of every shard that the keys are routed to
+    pub fn stats(&self) -> Stats{
+        Stats{shards: (0..self.shard_count()).map(|shard| self.lock_stats(shard).snapshot(shard)).collect()}
+    }
+
+}
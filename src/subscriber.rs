@@ -0,0 +1,61 @@
+
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use crate::error::{Result, S3Error};
+use crate::lock::{LockBackend, TokioMutex};
+use crate::map::{DefaultHashBuilder, Published, ShardedMap};
+
+
+/*
+
+    a receiver of the merged data that gets published by the reconciler,
+    a slow subscriber that has fallen behind the broadcast channel doesn't
+    keep serving stale data, instead it jumps to the tail of the channel and
+    resyncs itself from a full snapshot of the current shards, each resync
+    gets counted inside the metrics of the map, the subscriber only holds a
+    weak handle of the map so it doesn't keep the map and its channel alive.
+
+*/
+pub struct Subscriber<K, V, S = DefaultHashBuilder, B = TokioMutex> where
+    K: Send + Sync,
+    V: Send + Sync,
+    B: LockBackend
+{
+    map: Weak<ShardedMap<K, V, S, B>>,
+    receiver: broadcast::Receiver<Published<K, V>>,
+}
+
+impl<K, V, S, B> Subscriber<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    pub(crate) fn new(map: &Arc<ShardedMap<K, V, S, B>>, receiver: broadcast::Receiver<Published<K, V>>) -> Self{
+        Self{map: Arc::downgrade(map), receiver}
+    }
+
+    /// waits for the next published data, fails only once the map is gone
+    pub async fn recv(&mut self) -> Result<Published<K, V>>{
+        match self.receiver.recv().await{
+            Ok(published) => Ok(published),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "the subscriber has lagged behind, resyncing from a snapshot");
+                self.resync().await
+            },
+            Err(RecvError::Closed) => Err(S3Error::ChannelClosed),
+        }
+    }
+
+    async fn resync(&mut self) -> Result<Published<K, V>>{
+        let map = self.map.upgrade().ok_or(S3Error::ChannelClosed)?;
+        self.receiver = self.receiver.resubscribe();
+        map.metrics().record_lag_resync();
+        Ok(Arc::new(map.snapshot().await?))
+    }
+
+}
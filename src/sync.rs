@@ -0,0 +1,47 @@
+/*
+
+    the flags, the cursor and the layout pointer of the map that the loom
+    models have to see, they're the std atomics and an ArcSwap unless the
+    crate is built for the loom models (see tests/loom.rs), then they're
+    loom ones so loom switches threads on every access of them like it does
+    on the shard locks of the LoomMutex backend, a map built like that can
+    only be used inside a loom model.
+
+*/
+#[cfg(not(s3_loom))]
+pub(crate) use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+#[cfg(s3_loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicUsize};
+
+#[cfg(s3_loom)]
+use std::sync::Arc;
+
+
+#[cfg(not(s3_loom))]
+pub(crate) type Swap<T> = arc_swap::ArcSwap<T>;
+
+/// the ArcSwap of the loom models, a lock held only while the Arc is cloned or replaced
+#[cfg(s3_loom)]
+pub(crate) struct Swap<T>(loom::sync::Mutex<Arc<T>>);
+
+#[cfg(s3_loom)]
+impl<T> Swap<T>{
+
+    pub(crate) fn from_pointee(value: T) -> Self{
+        Self(loom::sync::Mutex::new(Arc::new(value)))
+    }
+
+    pub(crate) fn load(&self) -> Arc<T>{
+        self.load_full()
+    }
+
+    pub(crate) fn load_full(&self) -> Arc<T>{
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub(crate) fn store(&self, value: Arc<T>){
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = value;
+    }
+
+}
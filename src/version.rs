@@ -0,0 +1,101 @@
+
+
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// number of low bits of a stamp that are used by the logical counter
+const LOGICAL_BITS: u32 = 16;
+
+
+/*
+
+    a hybrid logical clock version, the stamp packs the wall clock millis
+    in its high bits and a logical counter in its low 16 bits so versions
+    generated in the same millisecond are still ordered, the node id breaks
+    the tie between two clocks that have generated the exact same stamp.
+
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version{
+    pub stamp: u64,
+    pub node: u32,
+}
+
+impl Version{
+
+    pub fn physical_millis(&self) -> u64{
+        self.stamp >> LOGICAL_BITS
+    }
+
+    pub fn logical(&self) -> u64{
+        self.stamp & ((1 << LOGICAL_BITS) - 1)
+    }
+
+}
+
+/// a value stored inside a shard along with the version of the write that produced it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Versioned<V>{
+    pub value: V,
+    pub version: Version,
+}
+
+impl<V> Versioned<V>{
+
+    pub fn new(value: V, version: Version) -> Self{
+        Self{value, version}
+    }
+
+}
+
+
+/*
+
+    generates monotonic versions, every call to now() returns a version
+    greater than any other version that this clock has generated or observed
+    before even if the wall clock goes backward.
+
+*/
+#[derive(Debug, Default)]
+pub struct HybridClock{
+    node: u32,
+    last: AtomicU64,
+}
+
+impl HybridClock{
+
+    pub fn new(node: u32) -> Self{
+        Self{node, last: AtomicU64::new(0)}
+    }
+
+    pub fn node(&self) -> u32{
+        self.node
+    }
+
+    pub fn now(&self) -> Version{
+        let physical = physical_now() << LOGICAL_BITS;
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop{
+            let next = physical.max(last + 1);
+            match self.last.compare_exchange_weak(last, next, Ordering::AcqRel, Ordering::Relaxed){
+                Ok(_) => return Version{stamp: next, node: self.node},
+                Err(actual) => last = actual,
+            }
+        }
+    }
+
+    /// moves the clock forward so versions generated after this are newer than the remote one
+    pub fn observe(&self, remote: Version){
+        self.last.fetch_max(remote.stamp, Ordering::AcqRel);
+    }
+
+}
+
+fn physical_now() -> u64{
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,80 @@
+
+
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use crate::error::{Result, S3Error};
+use crate::lock::LockBackend;
+use crate::map::{Db, ShardDb, ShardedMap};
+use crate::reconcile;
+use crate::version::Versioned;
+
+
+/*
+
+    lock free reads of the pool, every shard has a view which is an Arc of
+    its db that can be swapped atomically (rcu style), readers just load the
+    current Arc of the shard and never wait on the shard lock, the writers
+    publish a new copy of the shard while they're still holding its lock so
+    the views of a shard are stored in the same order as the writes, by
+    default the views are only published by the reconciliation so they lag
+    behind the writes by one batch, with copy on write enabled every write
+    publishes a new view of its shard which costs a clone of the shard.
+
+*/
+impl<K, V, S, B> ShardedMap<K, V, S, B> where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher,
+    B: LockBackend
+{
+
+    /// the current view of the shard, it's not affected by the writes that come after it
+    pub fn shard_view(&self, shard: usize) -> Arc<ShardDb<K, V>>{
+        self.view(shard).load_full()
+    }
+
+    pub fn view_get(&self, key: &K) -> Result<V>{
+        self.view_get_versioned(key).map(|versioned| versioned.value)
+    }
+
+    /// the home of the key is checked again after loading the view in case a resharding has moved the key
+    pub fn view_get_versioned(&self, key: &K) -> Result<Versioned<V>>{
+        self.record_access(key);
+        loop{
+            let idx = self.shard_index(key);
+            let view = self.view(idx).load();
+            if self.shard_index(key) == idx{
+                return view.get(key).cloned().ok_or(S3Error::KeyNotFound);
+            }
+        }
+    }
+
+    /// total number of entries inside the views of all the shards
+    pub fn view_len(&self) -> usize{
+        self.views().map(|view| view.load().len()).sum()
+    }
+
+    /// a single db of the views merged like a reconciliation does, each shard is loaded on its own
+    pub fn view_snapshot(&self) -> Db<K, Versioned<V>>{
+        self.view_merged()
+    }
+
+    /// the views merged like the shards are merged by a reconciliation, without locking any shard
+    pub fn view_merged(&self) -> Db<K, Versioned<V>>{
+        let views = self.views().map(|view| view.load_full()).collect::<Vec<_>>();
+        reconcile::merge(views.iter().map(|view| &**view).enumerate(), |key| self.shard_index(key))
+    }
+
+    /// publishes a copy of the shard if copy on write is enabled, the caller must hold the shard lock
+    pub(crate) fn publish_view(&self, shard: usize, db: &ShardDb<K, V>){
+        if self.copy_on_write(){
+            self.store_view(shard, db);
+        }
+    }
+
+    pub(crate) fn store_view(&self, shard: usize, db: &ShardDb<K, V>){
+        self.view(shard).store(Arc::new(db.clone()));
+    }
+
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use s3::{S3Error, ShardedMap};
+
+
+#[tokio::test]
+async fn the_free_shards_are_handed_out_in_turn(){
+    let map = ShardedMap::<u32, u32>::new(4);
+    let mut indices = Vec::new();
+    for _ in 0..8{
+        indices.push(map.acquire_any_shard(Duration::from_secs(1)).await.unwrap().index());
+    }
+    assert_eq!(indices.iter().copied().collect::<BTreeSet<_>>(), (0..4).collect());
+    for pair in indices.windows(2){
+        assert_eq!(pair[1], (pair[0] + 1) % 4);
+    }
+}
+
+#[tokio::test]
+async fn a_locked_shard_is_skipped(){
+    let map = ShardedMap::<u32, u32>::new(4);
+    let shards = map.shards();
+    let _held = [shards[0].lock().await, shards[1].lock().await, shards[3].lock().await];
+    for _ in 0..4{
+        assert_eq!(map.acquire_any_shard(Duration::from_secs(1)).await.unwrap().index(), 2);
+    }
+}
+
+#[tokio::test]
+async fn waits_on_the_least_awaited_shard_once_all_are_busy(){
+    let map = ShardedMap::<u32, u32>::new(3);
+    let shards = map.shards();
+    let [first, second, third] = [shards[0].lock().await, shards[1].lock().await, shards[2].lock().await];
+    map.waiters(0).fetch_add(2, Ordering::Relaxed);
+    map.waiters(2).fetch_add(1, Ordering::Relaxed);
+
+    let (acquired, _) = tokio::join!(
+        map.acquire_any_shard(Duration::from_secs(5)),
+        async{
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // the other shards get freed first but nobody is waiting on them
+            drop((first, third));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(second);
+        }
+    );
+    assert_eq!(acquired.unwrap().index(), 1);
+}
+
+#[tokio::test]
+async fn times_out_when_every_shard_stays_busy(){
+    let map = ShardedMap::<u32, u32>::new(2);
+    let shards = map.shards();
+    let _held = [shards[0].lock().await, shards[1].lock().await];
+    let acquired = map.acquire_any_shard(Duration::from_millis(20)).await;
+    assert_eq!(acquired.err(), Some(S3Error::Timeout));
+    assert_eq!(map.waiters(0).load(Ordering::Relaxed) + map.waiters(1).load(Ordering::Relaxed), 0);
+}
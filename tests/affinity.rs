@@ -0,0 +1,78 @@
+use s3::{hash_tag, S3Error, ShardedMap};
+
+
+#[test]
+fn hash_tags_are_parsed_like_redis(){
+    assert_eq!(hash_tag("{user:1}:cart"), Some("user:1"));
+    assert_eq!(hash_tag("orders:{user:1}"), Some("user:1"));
+    assert_eq!(hash_tag("plain"), None);
+    // an empty tag means the whole key gets hashed
+    assert_eq!(hash_tag("{}:cart"), None);
+    assert_eq!(hash_tag("{user:1:cart"), None);
+    assert_eq!(hash_tag("}user{"), None);
+    // only the first tag counts
+    assert_eq!(hash_tag("{a}{b}"), Some("a"));
+    assert_eq!(hash_tag("x{a}y{b}"), Some("a"));
+    assert_eq!(hash_tag("{{a}}"), Some("{a"));
+}
+
+#[tokio::test]
+async fn keys_with_the_same_tag_share_their_home(){
+    let map = ShardedMap::<String, u32>::builder().shards(16).hash_tags().build::<String, u32>();
+    let keys = ["{user:1}:cart", "{user:1}:orders", "{user:1}", "x{user:1}y{user:2}"].map(String::from);
+    let home = map.shard_index(&keys[0]);
+    assert!(keys.iter().all(|key| map.shard_index(key) == home));
+
+    // the tag is still what places the keys once the pool is resharded
+    map.reshard(37).await.unwrap();
+    let home = map.shard_index(&keys[0]);
+    assert!(keys.iter().all(|key| map.shard_index(key) == home));
+
+    let mut gaurd = map.lock_keys(&keys).await.unwrap();
+    assert_eq!(gaurd.index(), home);
+    for (n, key) in keys.iter().enumerate(){
+        gaurd.insert(key.clone(), n as u32);
+    }
+    drop(gaurd);
+    for (n, key) in keys.iter().enumerate(){
+        assert_eq!(map.get(key).await, Ok(n as u32));
+    }
+}
+
+#[tokio::test]
+async fn locking_keys_of_different_homes_fails(){
+    let map = ShardedMap::<String, u32>::builder().shards(16).hash_tags().build::<String, u32>();
+    let first = String::from("{a}:1");
+    let other = (0..)
+        .map(|n| format!("{{b{}}}:1", n))
+        .find(|key| map.shard_index(key) != map.shard_index(&first))
+        .unwrap();
+
+    assert_eq!(map.lock_keys(&[first.clone(), other]).await.err(), Some(S3Error::CrossShard));
+    assert_eq!(map.lock_keys(&[]).await.err(), Some(S3Error::EmptyGroup));
+    // the failed attempts haven't kept anything locked
+    assert!(map.lock_keys(&[first]).await.is_ok());
+}
+
+#[tokio::test]
+async fn keys_of_the_same_group_share_their_home(){
+    // the orders of a user are grouped by the user, the other keys are routed by themselves
+    let map = ShardedMap::<(u32, u32), u32>::builder()
+        .shards(16)
+        .affinity(|&(user, order): &(u32, u32)| (order > 0).then_some(user as u64))
+        .build::<(u32, u32), u32>();
+    let orders = (1..20).map(|order| (7, order)).collect::<Vec<_>>();
+    let home = map.shard_index(&orders[0]);
+    assert!(orders.iter().all(|key| map.shard_index(key) == home));
+    assert!((0..64).map(|user| map.shard_index(&(user, 0))).any(|shard| shard != home));
+
+    map.reshard(5).await.unwrap();
+    let home = map.shard_index(&orders[0]);
+    assert!(orders.iter().all(|key| map.shard_index(key) == home));
+    let mut gaurd = map.lock_keys(&orders).await.unwrap();
+    for &key in &orders{
+        gaurd.insert(key, key.1);
+    }
+    drop(gaurd);
+    assert_eq!(map.get(&(7, 3)).await, Ok(3));
+}
@@ -0,0 +1,71 @@
+use s3::{BlockingBackend, LockBackend, ShardedMap, StdMutex, StdRwLock, TokioMutex, TokioRwLock};
+
+
+fn map<B: LockBackend>() -> ShardedMap<u32, u32, s3::DefaultHashBuilder, B>{
+    ShardedMap::<u32, u32>::builder().shards(4).backend::<B>().build()
+}
+
+async fn round_trip<B: LockBackend>(){
+    let map = map::<B>();
+    for key in 0..64u32{
+        assert_eq!(map.insert(key, key * 2).await, Ok(None));
+    }
+    assert_eq!(map.remove(&63).await, Ok(126));
+
+    let report = map.reshard(7).await.unwrap();
+    assert_eq!((report.from, report.to), (4, 7));
+    assert_eq!(map.shard_count(), 7);
+    for key in 0..63u32{
+        assert_eq!(map.get(&key).await, Ok(key * 2));
+    }
+    assert_eq!(map.reconcile().await.unwrap().len(), 63);
+    assert_eq!(map.len().await, Ok(63));
+}
+
+fn round_trip_blocking<B: BlockingBackend>(){
+    let map = map::<B>();
+    for key in 0..64u32{
+        assert_eq!(map.insert_blocking(key, key * 2), Ok(None));
+    }
+    assert_eq!(map.remove_blocking(&63), Ok(126));
+
+    let report = map.reshard_blocking(7).unwrap();
+    assert_eq!((report.from, report.to), (4, 7));
+    for key in 0..63u32{
+        assert_eq!(map.get_blocking(&key), Ok(key * 2));
+    }
+    assert_eq!(map.reconcile_blocking().unwrap().len(), 63);
+    assert_eq!(map.len_blocking(), Ok(63));
+}
+
+#[tokio::test]
+async fn tokio_backends_round_trip(){
+    round_trip::<TokioMutex>().await;
+    round_trip::<TokioRwLock>().await;
+}
+
+#[tokio::test]
+async fn std_backends_round_trip(){
+    round_trip::<StdMutex>().await;
+    round_trip::<StdRwLock>().await;
+}
+
+#[test]
+fn std_backends_round_trip_without_a_runtime(){
+    round_trip_blocking::<StdMutex>();
+    round_trip_blocking::<StdRwLock>();
+}
+
+#[cfg(feature = "parking_lot")]
+#[tokio::test]
+async fn parking_lot_backends_round_trip(){
+    round_trip::<s3::ParkingLotMutex>().await;
+    round_trip::<s3::ParkingLotRwLock>().await;
+}
+
+#[cfg(feature = "parking_lot")]
+#[test]
+fn parking_lot_backends_round_trip_without_a_runtime(){
+    round_trip_blocking::<s3::ParkingLotMutex>();
+    round_trip_blocking::<s3::ParkingLotRwLock>();
+}
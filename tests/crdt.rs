@@ -0,0 +1,130 @@
+use proptest::prelude::*;
+use s3::{CrdtMap, OrMap, Version};
+
+
+#[derive(Clone, Debug)]
+enum Op{
+    Insert(u8, u16),
+    Remove(u8),
+}
+
+fn op() -> impl Strategy<Value = Op>{
+    prop_oneof![
+        3 => (0..8u8, any::<u16>()).prop_map(|(key, value)| Op::Insert(key, value)),
+        1 => (0..8u8).prop_map(Op::Remove),
+    ]
+}
+
+/// builds a replica by applying the ops with versions that are unique for the node
+fn replica(node: u32, ops: &[(Op, u64)]) -> OrMap<u8, u16>{
+    let mut map = OrMap::new();
+    for (n, (op, stamp)) in ops.iter().enumerate(){
+        match op{
+            Op::Insert(key, value) => map.insert(*key, *value, Version{stamp: stamp * 64 + n as u64, node}),
+            Op::Remove(key) => {
+                map.remove(key);
+            }
+        }
+    }
+    map
+}
+
+fn ops() -> impl Strategy<Value = Vec<(Op, u64)>>{
+    prop::collection::vec((op(), 0..16u64), 0..24)
+}
+
+fn merged<K: Eq + std::hash::Hash + Clone, V: Clone>(a: &OrMap<K, V>, b: &OrMap<K, V>) -> OrMap<K, V>{
+    let mut merged = a.clone();
+    merged.merge(b);
+    merged
+}
+
+proptest!{
+
+    #[test]
+    fn merge_is_commutative(a in ops(), b in ops()){
+        let (a, b) = (replica(1, &a), replica(2, &b));
+        prop_assert_eq!(merged(&a, &b), merged(&b, &a));
+    }
+
+    #[test]
+    fn merge_is_associative(a in ops(), b in ops(), c in ops()){
+        let (a, b, c) = (replica(1, &a), replica(2, &b), replica(3, &c));
+        prop_assert_eq!(merged(&merged(&a, &b), &c), merged(&a, &merged(&b, &c)));
+    }
+
+    #[test]
+    fn merge_is_idempotent(a in ops(), b in ops()){
+        let a = replica(1, &a);
+        prop_assert_eq!(merged(&a, &a), a.clone());
+
+        let ab = merged(&a, &replica(2, &b));
+        prop_assert_eq!(merged(&ab, &a), ab.clone());
+    }
+
+}
+
+#[test]
+fn concurrent_add_wins_over_an_unobserved_remove(){
+    let mut a = OrMap::new();
+    a.insert("key", 1, Version{stamp: 1, node: 1});
+    let mut b = a.clone();
+
+    // a removes the key while b updates it without seeing the remove
+    assert_eq!(a.remove(&"key"), Some(1));
+    b.insert("key", 2, Version{stamp: 2, node: 2});
+
+    a.merge(&b);
+    assert_eq!(a.get(&"key"), Some(&2));
+
+    // once the update has been observed the remove wins
+    a.remove(&"key");
+    b.merge(&a);
+    assert!(!b.contains_key(&"key"));
+    assert!(b.is_empty());
+}
+
+#[test]
+fn a_removed_value_never_comes_back_with_a_concurrent_add(){
+    let mut a = OrMap::new();
+    let mut b = OrMap::new();
+
+    // a adds and removes x with a higher version than the concurrent add of y by b
+    a.insert("key", "x", Version{stamp: 5, node: 1});
+    assert_eq!(a.remove(&"key"), Some("x"));
+    b.insert("key", "y", Version{stamp: 3, node: 2});
+
+    let ab = merged(&a, &b);
+    assert_eq!(ab.get(&"key"), Some(&"y"));
+    assert_eq!(ab.get_register(&"key").map(|register| register.version()), Some(Version{stamp: 3, node: 2}));
+    assert_eq!(merged(&b, &a), ab);
+}
+
+#[tokio::test]
+async fn replicas_converge_in_any_order(){
+    let replicas = [CrdtMap::<u32, String>::new(4, 1), CrdtMap::new(7, 2), CrdtMap::new(1, 3)];
+    for (node, replica) in replicas.iter().enumerate(){
+        for key in 0..32u32{
+            replica.insert(key, format!("{node}:{key}")).await.unwrap();
+        }
+        for key in (node as u32..32).step_by(3){
+            replica.remove(&key).await.unwrap();
+        }
+    }
+
+    let [a, b, c] = &replicas;
+    a.merge(b).await.unwrap();
+    a.merge(c).await.unwrap();
+    c.merge(b).await.unwrap();
+    c.merge(a).await.unwrap();
+    b.merge(c).await.unwrap();
+
+    let expected = a.state().await.unwrap();
+    for replica in &replicas{
+        assert_eq!(replica.state().await.unwrap(), expected);
+        assert_eq!(replica.len().await, Ok(expected.len()));
+        for (key, register) in expected.iter(){
+            assert_eq!(replica.get(key).await.as_ref(), Ok(register.value()));
+        }
+    }
+}
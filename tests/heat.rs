@@ -0,0 +1,24 @@
+use std::time::Duration;
+use s3::ShardedMap;
+
+
+#[tokio::test]
+async fn skipped_shards_count_as_contended_attempts(){
+    let map = ShardedMap::<u32, u32>::new(2);
+    let busy = map.shards()[0].lock().await;
+    for _ in 0..8{
+        assert_eq!(map.acquire_any_shard(Duration::from_secs(1)).await.unwrap().index(), 1);
+    }
+    drop(busy);
+
+    let heat = map.hot_shards();
+    assert_eq!(heat[0].shard, 0);
+    assert_eq!(heat[0].acquisitions, 0);
+    assert!(heat[0].attempts > 0);
+    assert_eq!(heat[0].contended, heat[0].attempts);
+    assert_eq!(heat[0].contention(), 1.0);
+
+    assert_eq!(heat[1].acquisitions, 8);
+    assert_eq!(heat[1].attempts, 8);
+    assert_eq!(heat[1].contention(), 0.0);
+}
@@ -0,0 +1,254 @@
+#![cfg(s3_loom)]
+
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use loom::future::block_on;
+use loom::thread;
+use s3::{CancellationToken, LoomMutex, Reconciler, ShardedMap, Versioned};
+
+
+/*
+
+    loom models of the locking protocol of the map, the map itself is run
+    over the loom lock backend so every step is the real one (lock_home,
+    acquire_any_shard, notify, reconcile_dirty, refresh, migrate and the
+    swap of the published data), the cfg also turns the dirty flags, the
+    cursor, the layout pointer and the migrated flags of the map into loom
+    ones (see sync.rs), loom then runs every interleaving of the accesses
+    to them and to the shard locks, a lost update fails the assertions and
+    a deadlock fails the model itself, the admission semaphore, the updates
+    queue and the broadcast channel are still the tokio ones so loom never
+    switches threads inside them, the cfg keeps the loom types and the models
+    out of the regular builds:
+
+        RUSTFLAGS="--cfg s3_loom" cargo test --release --test loom
+
+    a plain --cfg loom can't be used since tokio has its own loom mode.
+
+*/
+fn model<F>(f: F) where
+    F: Fn() + Sync + Send + 'static
+{
+    let mut builder = loom::model::Builder::new();
+    //// LOOM_MAX_PREEMPTIONS overrides it, the whole space is too big for a test suite
+    builder.preemption_bound.get_or_insert(3);
+    builder.check(move || {
+        //// acquire_any_shard puts a deadline on its wait, the timer is never driven since no wait is that long
+        let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let _rt = rt.enter();
+        f()
+    });
+}
+
+
+/// the key is its own hash so a key lives inside the shard `key % shards` like in the other tests
+#[derive(Default)]
+struct Identity(u64);
+
+impl Hasher for Identity{
+    fn finish(&self) -> u64{
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]){
+        for &byte in bytes{
+            self.0 = self.0 << 8 | byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, key: u64){
+        self.0 = key;
+    }
+}
+
+type Map = ShardedMap<u64, u64, BuildHasherDefault<Identity>, LoomMutex>;
+
+/// a map of `shards` shards whose updates queue only has room for `capacity` of them
+fn map(shards: usize, capacity: usize) -> Arc<Map>{
+    Arc::new(ShardedMap::<u64, u64>::builder()
+        .shards(shards)
+        .updates_capacity(capacity)
+        .hasher(BuildHasherDefault::<Identity>::default())
+        .backend::<LoomMutex>()
+        .build())
+}
+
+/// the reconciler of the map on its own thread, it drains the queue once the token is cancelled
+fn reconciler(map: &Arc<Map>, token: &CancellationToken) -> thread::JoinHandle<()>{
+    let reconciler = Reconciler::new(map.clone()).unwrap();
+    let token = token.clone();
+    thread::spawn(move || block_on(reconciler.run(token)))
+}
+
+/// every copy of every key, in shard order
+fn copies(map: &Map) -> Vec<(usize, u64, u64)>{
+    let mut copies = Vec::new();
+    for (idx, shard) in map.shards().iter().enumerate(){
+        let gaurd = shard.lock().unwrap();
+        copies.extend(gaurd.iter().map(|(key, versioned)| (idx, *key, versioned.value)));
+    }
+    copies.sort();
+    copies
+}
+
+/// writes through the first free shard like any writer of acquire_any_shard, returns that shard
+fn insert_any(map: &Map, key: u64, value: Versioned<u64>) -> usize{
+    block_on(async{
+        let mut gaurd = map.acquire_any_shard(Duration::from_secs(60)).await.unwrap();
+        gaurd.insert_versioned(key, value);
+        gaurd.index()
+    })
+}
+
+
+/// a writer through the home shard and one through any free shard, with the reconciler behind them
+#[test]
+fn no_update_is_lost_between_the_writers_and_the_reconciler(){
+    model(|| {
+        //// a single slot so the second update is dropped whenever the first one is still pending
+        let map = map(2, 1);
+        let token = CancellationToken::new();
+        let reconciler = reconciler(&map, &token);
+        let home = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.insert(0, 1)).unwrap())
+        };
+        insert_any(&map, 1, Versioned::new(1, map.clock().now()));
+        home.join().unwrap();
+        token.cancel();
+        reconciler.join().unwrap();
+
+        assert_eq!(copies(&map), vec![(0, 0, 1), (1, 1, 1)]);
+        assert_eq!(map.view_get(&0), Ok(1));
+        assert_eq!(map.view_get(&1), Ok(1));
+    });
+}
+
+/// the stale copy that acquire_any_shard has left in another shard never wins over a later write
+#[test]
+fn a_later_write_wins_over_its_stale_copy(){
+    model(|| {
+        let map = map(2, 2);
+        let token = CancellationToken::new();
+        let reconciler = reconciler(&map, &token);
+        insert_any(&map, 1, Versioned::new(1, map.clock().now()));
+        block_on(map.insert(1, 2)).unwrap();
+        token.cancel();
+        reconciler.join().unwrap();
+
+        assert_eq!(copies(&map), vec![(1, 1, 2)]);
+        assert_eq!(block_on(map.get(&1)), Ok(2));
+        assert_eq!(map.view_get(&1), Ok(2));
+    });
+}
+
+/// a copy with the same version as the home one loses to it, whether the refresh comes before or after it
+#[test]
+fn the_home_copy_wins_on_the_same_version(){
+    model(|| {
+        let map = map(2, 4);
+        let (_, version) = block_on(map.insert_versioned(1, 1)).unwrap();
+        let writer = {
+            let map = map.clone();
+            thread::spawn(move || insert_any(&map, 1, Versioned::new(2, version)))
+        };
+        let merged = block_on(map.refresh()).unwrap();
+        assert_eq!(merged.get(&1).map(|versioned| versioned.value), Some(1));
+        //// the cursor starts on the first shard and the refresh never holds it while a writer is admitted
+        assert_eq!(writer.join().unwrap(), 0);
+        let merged = block_on(map.refresh()).unwrap();
+
+        assert_eq!(merged.get(&1).map(|versioned| versioned.value), Some(1));
+        assert_eq!(copies(&map), vec![(1, 1, 1)]);
+    });
+}
+
+/// an admitted writer waits for the refresh to give back every permit, so it never sees a half refreshed pool
+#[test]
+fn a_writer_never_sees_a_half_refreshed_pool(){
+    model(|| {
+        let map = map(2, 4);
+        insert_any(&map, 1, Versioned::new(1, map.clock().now()));
+        let writer = {
+            let map = map.clone();
+            thread::spawn(move || {
+                let _permit = block_on(map.write_permit()).unwrap();
+                copies(&map)
+            })
+        };
+        block_on(map.refresh()).unwrap();
+        let seen = writer.join().unwrap();
+
+        assert!(seen == vec![(0, 1, 1)] || seen == vec![(1, 1, 1)], "{:?}", seen);
+        assert_eq!(copies(&map), vec![(1, 1, 1)]);
+    });
+}
+
+/// the keys written while the pool grows end up inside their new home, once
+#[test]
+fn no_update_is_lost_while_resharding(){
+    model(|| {
+        let map = map(2, 4);
+        let resharder = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.reshard(3)).unwrap())
+        };
+        block_on(map.insert(2, 1)).unwrap();
+        block_on(map.insert(2, 2)).unwrap();
+        assert_eq!(block_on(map.get(&2)), Ok(2));
+        resharder.join().unwrap();
+
+        assert_eq!(copies(&map), vec![(2, 2, 2)]);
+        assert_eq!(block_on(map.get(&2)), Ok(2));
+    });
+}
+
+/// the reconciler and a shrinking resharding lock several shards while the writer fills up the queue,
+/// a writer that waited on the full queue while holding its shard would deadlock with the reconciler
+#[test]
+fn reconciling_while_resharding_doesnt_deadlock(){
+    model(|| {
+        let map = map(2, 1);
+        let token = CancellationToken::new();
+        let reconciler = reconciler(&map, &token);
+        let resharder = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.reshard(1)).unwrap())
+        };
+        block_on(map.insert(1, 1)).unwrap();
+        block_on(map.insert(0, 1)).unwrap();
+        block_on(map.insert(1, 2)).unwrap();
+        resharder.join().unwrap();
+        token.cancel();
+        reconciler.join().unwrap();
+
+        assert_eq!(copies(&map), vec![(0, 0, 1), (0, 1, 2)]);
+        assert_eq!(block_on(map.get(&1)), Ok(2));
+    });
+}
+
+/// a subscriber that still holds the old published data keeps reading it while it's being swapped
+#[test]
+fn the_published_data_is_swapped_as_a_whole(){
+    model(|| {
+        let map = map(2, 2);
+        let mut subscriber = map.subscribe();
+        block_on(map.insert(0, 1)).unwrap();
+        map.publish(block_on(map.refresh()).unwrap());
+        let writer = {
+            let map = map.clone();
+            thread::spawn(move || {
+                block_on(map.insert(1, 1)).unwrap();
+                map.publish(block_on(map.refresh()).unwrap());
+            })
+        };
+        let held = block_on(subscriber.recv()).unwrap();
+        assert_eq!(held.get(&0).map(|versioned| versioned.value), Some(1));
+        let len = held.len();
+        writer.join().unwrap();
+
+        assert_eq!(held.len(), len);
+        assert_eq!(block_on(subscriber.recv()).unwrap().len(), 2);
+    });
+}
@@ -0,0 +1,40 @@
+use s3::{Placement, ShardedMap};
+
+
+const KEYS: u64 = 4096;
+
+/// grows the pool from 8 to 9 shards and returns the number of keys that have been moved
+async fn moved_by_growing(placement: Placement) -> usize{
+    let map = ShardedMap::<u64, u64>::builder().shards(8).placement(placement).build();
+    for key in 0..KEYS{
+        map.insert(key, key).await.unwrap();
+    }
+    let homes = (0..KEYS).map(|key| map.shard_index(&key)).collect::<Vec<_>>();
+
+    let report = map.reshard(9).await.unwrap();
+    assert_eq!(report.moved, (0..KEYS).filter(|key| map.shard_index(key) != homes[*key as usize]).count());
+    assert_eq!(report.moved as u64, map.metrics().moved_keys());
+    for key in 0..KEYS{
+        assert_eq!(map.get(&key).await, Ok(key));
+    }
+
+    // the metrics add up the moves of every resharding
+    let shrunk = map.reshard(8).await.unwrap();
+    assert_eq!((report.moved + shrunk.moved) as u64, map.metrics().moved_keys());
+    assert_eq!(map.metrics().reshards(), 2);
+    report.moved
+}
+
+#[tokio::test]
+async fn consistent_placements_move_far_fewer_keys_than_modulo(){
+    let modulo = moved_by_growing(Placement::Modulo).await;
+    let ring = moved_by_growing(Placement::ring(128)).await;
+    let rendezvous = moved_by_growing(Placement::Rendezvous).await;
+
+    // modulo rehomes about 8 keys out of 9, the others about 1 out of 9
+    let keys = KEYS as usize;
+    assert!(modulo > keys / 2, "modulo has only moved {} keys", modulo);
+    assert!(ring < keys / 4, "the ring has moved {} keys", ring);
+    assert!(rendezvous < keys / 4, "rendezvous has moved {} keys", rendezvous);
+    assert!(ring * 3 < modulo && rendezvous * 3 < modulo);
+}
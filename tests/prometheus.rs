@@ -0,0 +1,98 @@
+#![cfg(feature = "prometheus")]
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use s3::ShardedMap;
+
+
+async fn get(addr: std::net::SocketAddr, path: &str) -> String{
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn the_metrics_endpoint_is_scraped(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(4));
+    for key in 0..10{
+        map.insert(key, key).await.unwrap();
+    }
+    map.get(&3).await.unwrap();
+    let addr = map.serve_metrics("127.0.0.1:0").await.unwrap();
+
+    let response = get(addr, "/metrics").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8"));
+    assert!(head.contains(&format!("Content-Length: {}", body.len())));
+
+    let families = [
+        ("s3_shard_entries", "gauge"),
+        ("s3_shard_operations_total", "counter"),
+        ("s3_shard_lock_acquisitions_total", "counter"),
+        ("s3_shard_try_lock_failures_total", "counter"),
+        ("s3_shard_lock_wait_seconds", "summary"),
+        ("s3_shard_lock_hold_seconds", "summary"),
+        ("s3_updates_queued", "gauge"),
+        ("s3_publishes_queued", "gauge"),
+        ("s3_subscribers", "gauge"),
+        ("s3_shards", "gauge"),
+        ("s3_reconciliations_total", "counter"),
+        ("s3_lag_resyncs_total", "counter"),
+        ("s3_reshards_total", "counter"),
+        ("s3_moved_keys_total", "counter"),
+    ];
+    let lines = body.lines().collect::<Vec<_>>();
+    for (name, kind) in families{
+        let help = lines.iter().position(|line| line.starts_with(&format!("# HELP {} ", name)))
+            .unwrap_or_else(|| panic!("{} has no HELP line", name));
+        assert_eq!(lines[help + 1], format!("# TYPE {} {}", name, kind));
+        assert!(lines[help + 2].starts_with(name), "{} has no sample", name);
+    }
+    // every sample belongs to the family announced above it
+    let mut family = "";
+    for line in &lines{
+        match line.strip_prefix("# TYPE "){
+            Some(typed) => family = typed.split(' ').next().unwrap(),
+            None if !line.starts_with('#') => assert!(line.starts_with(family), "{} is outside of its family", line),
+            None => {},
+        }
+    }
+
+    assert!(lines.contains(&"s3_shards 4"));
+    let entries = lines.iter()
+        .filter_map(|line| line.strip_prefix("s3_shard_entries{"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+        .sum::<usize>();
+    assert_eq!(entries, 10);
+    let gets = lines.iter()
+        .filter(|line| line.starts_with("s3_shard_operations_total{") && line.contains("op=\"get\""))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        .sum::<u64>();
+    assert_eq!(gets, 1);
+    assert!(lines.iter().any(|line| line.starts_with("s3_shard_lock_wait_seconds{shard=\"0\",quantile=\"0.99\"}")));
+
+    assert!(get(addr, "/other").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(map.shutdown().await.is_clean());
+}
+
+#[tokio::test]
+async fn the_shutdown_closes_the_idle_connections(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(2));
+    let addr = map.serve_metrics("127.0.0.1:0").await.unwrap();
+
+    // a client that never sends its request
+    let mut idle = TcpStream::connect(addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let report = tokio::time::timeout(Duration::from_secs(2), map.shutdown()).await.unwrap();
+    assert!(report.is_clean());
+
+    let mut buf = [0; 16];
+    let read = tokio::time::timeout(Duration::from_secs(2), idle.read(&mut buf)).await
+        .expect("the idle connection is still open");
+    assert_eq!(read.unwrap(), 0);
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use s3::{reconcile, CancellationToken, Reconciler, S3Error, ShardedMap, Version, Versioned};
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn no_insert_is_lost_under_concurrent_writers(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(8));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let reconciler = {
+        let map = map.clone();
+        let done = done.clone();
+        tokio::spawn(async move{
+            let mut runs = 0;
+            while !done.load(Ordering::Acquire){
+                map.reconcile().await.unwrap();
+                runs += 1;
+                tokio::task::yield_now().await;
+            }
+            runs
+        })
+    };
+
+    let writers = (0..16u32).map(|writer| {
+        let map = map.clone();
+        tokio::spawn(async move{
+            for n in 0..200u32{
+                let key = writer * 1_000 + n;
+                if n % 2 == 0{
+                    map.insert(key, n).await.unwrap();
+                } else{
+                    // write straight into a shard which is not the home of the key
+                    let stray = (map.shard_index(&key) + 1) % map.shard_count();
+                    map.shards()[stray].lock().await.insert(key, Versioned::new(n, map.clock().now()));
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for writer in writers{
+        writer.await.unwrap();
+    }
+    done.store(true, Ordering::Release);
+    assert!(reconciler.await.unwrap() > 0);
+
+    let merged = map.reconcile().await.unwrap();
+    assert_eq!(merged.len(), 16 * 200);
+    for writer in 0..16u32{
+        for n in 0..200u32{
+            let key = writer * 1_000 + n;
+            assert_eq!(merged.get(&key).map(|v| v.value), Some(n));
+            assert_eq!(map.get(&key).await, Ok(n), "key {key} is not in its home shard");
+        }
+    }
+    assert_eq!(map.len().await, Ok(16 * 200));
+}
+
+fn at<V>(value: V, stamp: u64) -> Versioned<V>{
+    Versioned::new(value, Version{stamp, node: 0})
+}
+
+#[tokio::test]
+async fn versions_are_exposed_and_newer_writes_win(){
+    let map = ShardedMap::<&str, &str>::new(4);
+    let (_, first) = map.insert_versioned("key", "old").await.unwrap();
+    let stray = (map.shard_index(&"key") + 1) % map.shard_count();
+    map.shards()[stray].lock().await.insert("key", Versioned::new("new", map.clock().now()));
+
+    let merged = map.reconcile().await.unwrap();
+    let versioned = map.get_versioned(&"key").await.unwrap();
+    assert_eq!(versioned.value, "new");
+    assert!(versioned.version > first);
+    assert_eq!(merged.get("key"), Some(&versioned));
+
+    // a stray copy older than the home one is dropped
+    let (_, latest) = map.insert_versioned("key", "latest").await.unwrap();
+    map.shards()[stray].lock().await.insert("key", Versioned::new("stale", first));
+    map.reconcile().await.unwrap();
+    assert_eq!(map.get_versioned(&"key").await, Ok(Versioned::new("latest", latest)));
+}
+
+#[test]
+fn highest_version_wins_conflicts(){
+    let home = |_: &&str| 2;
+    let first = HashMap::from([("key", at("newest", 9))]);
+    let second = HashMap::from([("key", at("home", 3))]);
+    let third = HashMap::from([("other", at("value", 1))]);
+
+    let merged = reconcile::merge([(0, &first), (2, &second), (3, &third)], home);
+    assert_eq!(merged.get("key"), Some(&at("newest", 9)));
+    assert_eq!(merged.get("other"), Some(&at("value", 1)));
+
+    // the order of the shards doesn't matter
+    let merged = reconcile::merge([(3, &third), (2, &second), (0, &first)], home);
+    assert_eq!(merged.get("key"), Some(&at("newest", 9)));
+}
+
+#[test]
+fn home_shard_copy_wins_on_the_same_version(){
+    let home = |_: &&str| 2;
+    let first = HashMap::from([("key", at("stray", 5))]);
+    let second = HashMap::from([("key", at("home", 5))]);
+
+    let merged = reconcile::merge([(0, &first), (2, &second)], home);
+    assert_eq!(merged.get("key"), Some(&at("home", 5)));
+}
+
+#[test]
+fn lowest_shard_wins_without_a_home_copy(){
+    let home = |_: &&str| 0;
+    let first = HashMap::from([("key", at("one", 5))]);
+    let second = HashMap::from([("key", at("three", 5))]);
+
+    let merged = reconcile::merge([(3, &second), (1, &first)], home);
+    assert_eq!(merged.get("key"), Some(&at("one", 5)));
+
+    let parts = reconcile::partition(&merged, 4, home);
+    assert_eq!(parts[0].get("key"), Some(&at("one", 5)));
+    assert!(parts[1..].iter().all(|part| part.is_empty()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn the_reconciler_only_locks_the_dirty_shards(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(4));
+    let mut subscriber = map.subscribe();
+    map.spawn_reconciler().unwrap();
+
+    let home = map.shard_index(&7);
+    let (stray, busy) = ((home + 1) % 4, (home + 2) % 4);
+    let shards = map.shards();
+    // a shard which has not been written stays locked during the whole reconciliation
+    let _busy = shards[busy].lock().await;
+    shards[stray].lock().await.insert(7, Versioned::new(70, map.clock().now()));
+    map.notify(stray).unwrap();
+
+    let published = tokio::time::timeout(Duration::from_secs(5), subscriber.recv()).await
+        .expect("the reconciler is waiting for a clean shard")
+        .unwrap();
+    assert_eq!(published.get(&7).map(|v| v.value), Some(70));
+    assert!(!shards[stray].lock().await.contains_key(&7));
+    assert_eq!(shards[home].lock().await.get(&7).map(|v| v.value), Some(70));
+    assert!(map.metrics().reconciliations() > 0);
+}
+
+#[tokio::test]
+async fn the_whole_pool_is_refreshed_every_refresh_interval(){
+    let map = Arc::new(ShardedMap::<u32, u32>::builder().shards(4).refresh_interval(Duration::from_millis(10)).build());
+    map.spawn_reconciler().unwrap();
+
+    // nobody tells the reconciler about this one
+    let stray = (map.shard_index(&7) + 1) % 4;
+    map.shards()[stray].lock().await.insert(7, Versioned::new(70, map.clock().now()));
+
+    tokio::time::timeout(Duration::from_secs(5), async{
+        while map.get(&7).await.is_err(){
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("the pool has never been refreshed");
+    assert_eq!(map.get(&7).await, Ok(70));
+}
+
+#[tokio::test]
+async fn the_reconciler_stops_once_the_map_is_dropped(){
+    let map = Arc::new(ShardedMap::<u32, u32>::builder().shards(4).refresh_interval(Duration::from_millis(10)).build());
+    let reconciler = Reconciler::new(map.clone()).unwrap().spawn(CancellationToken::new());
+    let mut subscriber = map.subscribe();
+    map.insert(7, 70).await.unwrap();
+
+    // no shutdown, every handle of the map is just dropped
+    drop(map);
+    tokio::time::timeout(Duration::from_secs(5), reconciler).await
+        .expect("the reconciler keeps the map alive")
+        .unwrap();
+    // whatever got published before is still there, then the channel is closed
+    let closed = loop{
+        if let Err(e) = subscriber.recv().await{
+            break e;
+        }
+    };
+    assert_eq!(closed, S3Error::ChannelClosed);
+}
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use s3::{ShardedMap, Versioned, WritePermit};
+
+
+const KEYS: u32 = 64;
+
+fn map() -> Arc<ShardedMap<u32, u32>>{
+    Arc::new(ShardedMap::<u32, u32>::builder().shards(8).max_writers(16).build())
+}
+
+/// takes every permit of the pool one by one so nobody else can write
+async fn exclusive(map: &ShardedMap<u32, u32>) -> Vec<WritePermit<'_>>{
+    let mut permits = Vec::new();
+    for _ in 0..map.max_writers(){
+        permits.push(map.write_permit().await.unwrap());
+    }
+    permits
+}
+
+/// moves every key out of its home shard
+async fn scatter(map: &ShardedMap<u32, u32>){
+    let _all = exclusive(map).await;
+    let mut moved = Vec::new();
+    for shard in map.shards(){
+        moved.extend(shard.lock().await.drain());
+    }
+    for (key, value) in moved{
+        let stray = (map.shard_index(&key) + 1) % map.shard_count();
+        map.shards()[stray].lock().await.insert(key, value);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn writers_never_observe_a_half_updated_pool(){
+    let map = map();
+    for key in 0..KEYS{
+        let stray = (map.shard_index(&key) + 1) % map.shard_count();
+        map.shards()[stray].lock().await.insert(key, Versioned::new(key, map.clock().now()));
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writers = (0..8).map(|_| {
+        let map = map.clone();
+        let done = done.clone();
+        tokio::spawn(async move{
+            let mut observed = 0;
+            while !done.load(Ordering::Acquire){
+                let _permit = map.write_permit().await.unwrap();
+
+                // walk the pool shard by shard while being admitted as a writer
+                let mut home = 0;
+                let mut seen = 0;
+                for (idx, shard) in map.shards().iter().enumerate(){
+                    for key in shard.lock().await.keys(){
+                        seen += 1;
+                        if map.shard_index(key) == idx{
+                            home += 1;
+                        }
+                    }
+                    tokio::task::yield_now().await;
+                }
+                assert_eq!(seen, KEYS, "a key is missing or duplicated");
+                assert!(home == 0 || home == KEYS, "half updated pool: {home} of {KEYS} keys are home");
+                observed += 1;
+            }
+            observed
+        })
+    }).collect::<Vec<_>>();
+
+    for _ in 0..50{
+        map.refresh().await.unwrap();
+        tokio::task::yield_now().await;
+        scatter(&map).await;
+        tokio::task::yield_now().await;
+    }
+    done.store(true, Ordering::Release);
+
+    for writer in writers{
+        assert!(writer.await.unwrap() > 0);
+    }
+
+    let merged = map.refresh().await.unwrap();
+    assert_eq!(merged.len(), KEYS as usize);
+    for key in 0..KEYS{
+        assert_eq!(map.get(&key).await, Ok(key));
+    }
+}
+
+#[tokio::test]
+async fn refresh_waits_for_writers_and_readmits_them_afterwards(){
+    let map = map();
+    map.insert(1, 1).await.unwrap();
+
+    let in_flight = map.write_permit().await.unwrap();
+    let refreshed = Arc::new(AtomicBool::new(false));
+    let refresh = {
+        let map = map.clone();
+        let refreshed = refreshed.clone();
+        tokio::spawn(async move{
+            map.refresh().await.unwrap();
+            refreshed.store(true, Ordering::Release);
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!refreshed.load(Ordering::Acquire), "refresh didn't wait for the in-flight writer");
+
+    // a writer that shows up during the refresh phase is admitted only after it
+    let late_writer = {
+        let map = map.clone();
+        tokio::spawn(async move{
+            map.insert(2, 2).await.unwrap();
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!late_writer.is_finished(), "a writer has been admitted during the refresh phase");
+
+    drop(in_flight);
+    refresh.await.unwrap();
+    assert!(refreshed.load(Ordering::Acquire));
+    late_writer.await.unwrap();
+    assert_eq!(map.get(&2).await, Ok(2));
+}
+
+#[tokio::test]
+async fn the_reconciler_moves_the_stray_keys_only_while_no_writer_is_admitted(){
+    let map = map();
+    map.spawn_reconciler().unwrap();
+    let (home, stray) = (map.shard_index(&7), (map.shard_index(&7) + 1) % map.shard_count());
+
+    let in_flight = map.write_permit().await.unwrap();
+    map.shards()[stray].lock().await.insert(7, Versioned::new(70, map.clock().now()));
+    map.notify(stray).unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(map.shards()[stray].lock().await.contains_key(&7), "the reconciler has moved a key while a writer was admitted");
+
+    drop(in_flight);
+    tokio::time::timeout(Duration::from_secs(5), async{
+        while !map.shards()[home].lock().await.contains_key(&7){
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }).await.expect("the stray key has never been moved home");
+    assert!(!map.shards()[stray].lock().await.contains_key(&7));
+}
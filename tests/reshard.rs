@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use rand::Rng;
+use s3::{S3Error, ShardedMap};
+
+
+const WRITERS: u64 = 4;
+const READERS: usize = 4;
+
+fn key(writer: u64, n: u64) -> u64{
+    writer * 1_000_000 + n
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn no_key_is_lost_while_resharding_under_load(){
+    let map = Arc::new(ShardedMap::<u64, u64>::builder().shards(10).copy_on_write(true).build());
+    map.spawn_reconciler().unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let written = Arc::new((0..WRITERS).map(|_| AtomicU64::new(0)).collect::<Vec<_>>());
+
+    let writers = (0..WRITERS).map(|writer| {
+        let (map, done, written) = (map.clone(), done.clone(), written.clone());
+        tokio::spawn(async move{
+            let mut n = 0;
+            while !done.load(Ordering::Acquire){
+                map.insert(key(writer, n), n).await.unwrap();
+                n += 1;
+                written[writer as usize].store(n, Ordering::Release);
+                if n % 64 == 0{
+                    tokio::task::yield_now().await;
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    let readers = (0..READERS).map(|_| {
+        let (map, done, written) = (map.clone(), done.clone(), written.clone());
+        tokio::spawn(async move{
+            let mut reads = 0u64;
+            while !done.load(Ordering::Acquire){
+                let writer = rand::thread_rng().gen_range(0..WRITERS);
+                let count = written[writer as usize].load(Ordering::Acquire);
+                if count > 0{
+                    // every write that has returned must be visible to the readers
+                    let n = rand::thread_rng().gen_range(0..count);
+                    assert_eq!(map.get(&key(writer, n)).await, Ok(n), "lost {} during resharding", key(writer, n));
+                    assert_eq!(map.view_get(&key(writer, n)), Ok(n), "{} is missing from the views", key(writer, n));
+                    reads += 1;
+                }
+                tokio::task::yield_now().await;
+            }
+            reads
+        })
+    }).collect::<Vec<_>>();
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    map.reshard(64).await.unwrap();
+    assert_eq!(map.shard_count(), 64);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    map.reshard(4).await.unwrap();
+    assert_eq!(map.shard_count(), 4);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    done.store(true, Ordering::Release);
+
+    for writer in writers{
+        writer.await.unwrap();
+    }
+    for reader in readers{
+        assert!(reader.await.unwrap() > 0);
+    }
+
+    let mut total = 0;
+    for writer in 0..WRITERS{
+        let count = written[writer as usize].load(Ordering::Acquire);
+        for n in 0..count{
+            assert_eq!(map.get(&key(writer, n)).await, Ok(n));
+        }
+        total += count as usize;
+    }
+    assert_eq!(map.len().await, Ok(total));
+    assert_eq!(map.view_len(), total);
+
+    // every key is inside its home shard and the retired shards are empty
+    map.refresh().await.unwrap();
+    assert_eq!(map.shards().len(), 64);
+    for (idx, shard) in map.shards().iter().enumerate(){
+        for key in shard.lock().await.keys(){
+            assert_eq!(map.shard_index(key), idx);
+        }
+    }
+
+    assert!(map.shutdown().await.is_clean());
+}
+
+#[tokio::test]
+async fn split_shard_only_moves_the_keys_of_the_split_shard(){
+    let map = ShardedMap::<u64, u64>::new(4);
+    for key in 0..256{
+        map.insert(key, key).await.unwrap();
+    }
+    let homes = (0..256).map(|key| map.shard_index(&key)).collect::<Vec<_>>();
+
+    let report = map.split_shard(1, 3).await.unwrap();
+    assert_eq!((report.from, report.to), (4, 6));
+    assert_eq!(map.shard_count(), 6);
+    assert_eq!(report.moved, (0..256).filter(|key| map.shard_index(key) != homes[*key as usize]).count());
+    assert!(report.moved > 0);
+
+    let mut parts = std::collections::BTreeSet::new();
+    for key in 0..256u64{
+        let home = map.shard_index(&key);
+        match homes[key as usize]{
+            1 => {
+                assert!(matches!(home, 1 | 4 | 5), "key {} has left the split shard for {}", key, home);
+                parts.insert(home);
+            },
+            before => assert_eq!(home, before, "key {} of an untouched shard has moved", key),
+        }
+        assert_eq!(map.get(&key).await, Ok(key));
+        assert!(map.shards()[home].lock().await.contains_key(&key));
+    }
+    assert_eq!(parts.len(), 3);
+    assert_eq!(map.len().await, Ok(256));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn overwrites_that_race_the_migration_are_kept(){
+    let map = Arc::new(ShardedMap::<u64, u64>::new(4));
+    let done = Arc::new(AtomicBool::new(false));
+
+    // every writer keeps overwriting its own few keys so the migrating keys are also being written
+    let writers = (0..WRITERS).map(|writer| {
+        let (map, done) = (map.clone(), done.clone());
+        tokio::spawn(async move{
+            let mut n = 0;
+            while !done.load(Ordering::Acquire) || n < 1_000{
+                map.insert(key(writer, n % 16), n).await.unwrap();
+                n += 1;
+                tokio::task::yield_now().await;
+            }
+            n
+        })
+    }).collect::<Vec<_>>();
+
+    map.split_shard(2, 4).await.unwrap();
+    map.reshard(9).await.unwrap();
+    map.split_shard(0, 2).await.unwrap();
+    map.reshard(3).await.unwrap();
+    done.store(true, Ordering::Release);
+
+    for (writer, handle) in writers.into_iter().enumerate(){
+        let written = handle.await.unwrap();
+        for n in written - 16..written{
+            assert_eq!(map.get(&key(writer as u64, n % 16)).await, Ok(n), "the last write of writer {} got lost", writer);
+        }
+    }
+    assert_eq!(map.len().await, Ok(WRITERS as usize * 16));
+}
+
+#[tokio::test]
+async fn invalid_reshards_are_errors(){
+    let map = ShardedMap::<u64, u64>::new(4);
+    assert!(matches!(map.reshard(0).await, Err(S3Error::InvalidReshard(_))));
+    assert!(matches!(map.split_shard(0, 1).await, Err(S3Error::InvalidReshard(_))));
+    assert!(matches!(map.split_shard(4, 2).await, Err(S3Error::InvalidReshard(_))));
+    assert_eq!(map.shard_count(), 4);
+    assert_eq!(map.metrics().reshards(), 0);
+}
+
+#[tokio::test]
+async fn shards_are_borrowed_from_the_map(){
+    let map = ShardedMap::<u64, u64>::new(2);
+    let gaurd = map.shards()[0].try_lock().unwrap();
+    assert!(map.shards().get(0).unwrap().try_lock().is_err());
+    assert!(map.shards().get(2).is_none());
+    drop(gaurd);
+    assert!(map.shards().iter().all(|shard| shard.try_lock().is_ok()));
+}
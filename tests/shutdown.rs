@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use s3::{S3Error, ShardedMap};
+
+
+#[tokio::test]
+async fn a_write_after_the_shutdown_fails_without_changing_the_map(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(4));
+    map.spawn_reconciler().unwrap();
+    map.insert(1, 10).await.unwrap();
+    assert!(map.shutdown().await.is_clean());
+
+    assert_eq!(map.insert(2, 20).await, Err(S3Error::ChannelClosed));
+    assert_eq!(map.get(&2).await, Err(S3Error::KeyNotFound));
+    assert_eq!(map.remove(&1).await, Err(S3Error::ChannelClosed));
+    assert_eq!(map.get(&1).await, Ok(10));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_write_racing_the_shutdown_is_either_stored_or_refused(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(4));
+    map.spawn_reconciler().unwrap();
+
+    let writers = (0..4u32).map(|writer| {
+        let map = map.clone();
+        tokio::spawn(async move{
+            let mut results = Vec::new();
+            for n in 0..500u32{
+                let key = writer * 1_000 + n;
+                results.push((key, map.insert(key, n).await));
+                tokio::task::yield_now().await;
+            }
+            results
+        })
+    }).collect::<Vec<_>>();
+    tokio::task::yield_now().await;
+    assert!(map.shutdown().await.is_clean());
+
+    for writer in writers{
+        for (key, result) in writer.await.unwrap(){
+            match result{
+                Ok(_) => assert_eq!(map.get(&key).await, Ok(key % 1_000), "key {key} has been acknowledged but not stored"),
+                Err(e) => {
+                    assert_eq!(e, S3Error::ChannelClosed);
+                    assert_eq!(map.get(&key).await, Err(S3Error::KeyNotFound), "key {key} has been refused but stored");
+                }
+            }
+        }
+    }
+}
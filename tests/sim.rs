@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::time::Duration;
+use s3::sim::{self, SimReport, Simulation};
+use s3::{CancellationToken, Reconciler, ShardedMap};
+
+
+/// writers, a resharder and the reconciler racing on a fresh map, returns what the map holds at the end
+async fn workload() -> Vec<(u32, u32)>{
+    let map = Arc::new(ShardedMap::<u32, u32>::new(4));
+    let token = CancellationToken::new();
+    let reconciler = sim::spawn(Reconciler::new(map.clone()).unwrap().run(token.clone()));
+
+    let writers = (0..4u32).map(|writer| {
+        let map = map.clone();
+        sim::spawn(async move{
+            for n in 0..50u32{
+                let key = sim::random::<u32>() % 16 * 4 + writer;
+                map.insert(key, n).await.unwrap();
+                sim::sleep(Duration::from_micros(sim::random::<u64>() % 20)).await;
+            }
+        })
+    }).collect::<Vec<_>>();
+    let resharder = {
+        let map = map.clone();
+        sim::spawn(async move{
+            sim::sleep(Duration::from_micros(300)).await;
+            map.reshard(7).await.unwrap();
+        })
+    };
+
+    for writer in writers{
+        writer.await;
+    }
+    resharder.await;
+    token.cancel();
+    reconciler.await;
+
+    let mut stored = map.snapshot().await.unwrap()
+        .into_iter()
+        .map(|(key, versioned)| (key, versioned.value))
+        .collect::<Vec<_>>();
+    stored.sort();
+    stored
+}
+
+fn simulate(seed: u64) -> (Vec<(u32, u32)>, SimReport){
+    //// the executor must not run on a thread of the tokio runtime
+    std::thread::spawn(move || Simulation::new(seed).run(workload()).unwrap()).join().unwrap()
+}
+
+#[test]
+fn the_same_seed_replays_the_same_run(){
+    for seed in 0..4{
+        let (stored, report) = simulate(seed);
+        let (replayed, replay) = simulate(seed);
+        assert_eq!(report, replay, "seed {} has been scheduled differently", seed);
+        assert_eq!(stored, replayed);
+        assert_eq!(report.seed, seed);
+    }
+    assert_ne!(simulate(0).1.fingerprint, simulate(1).1.fingerprint);
+}
+
+#[test]
+fn no_sleeper_is_lost_when_deadlines_collide(){
+    for seed in 0..16{
+        let (woken, _) = Simulation::new(seed).run(async{
+            let sleepers = (0..32).map(|_| sim::spawn(async{
+                for _ in 0..50{
+                    // every task sleeps until the next 10µs tick so they share their deadlines
+                    let tick = 10_000 - sim::now().as_nanos() as u64 % 10_000;
+                    sim::sleep(Duration::from_nanos(tick + sim::random::<u64>() % 3 * 10_000)).await;
+                }
+            })).collect::<Vec<_>>();
+            let mut woken = 0;
+            for sleeper in sleepers{
+                sleeper.await;
+                woken += 1;
+            }
+            woken
+        }).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(woken, 32);
+    }
+}
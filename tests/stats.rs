@@ -0,0 +1,65 @@
+use std::sync::Arc;
+use std::time::Duration;
+use s3::ShardedMap;
+
+
+#[tokio::test]
+async fn writes_through_a_shard_guard_are_counted(){
+    let map = ShardedMap::<u32, u32>::new(1);
+    {
+        let mut gaurd = map.acquire_any_shard(Duration::from_secs(1)).await.unwrap();
+        for key in 0..3{
+            gaurd.insert(key, key);
+        }
+        assert!(gaurd.remove(&0).is_some());
+    }
+    {
+        let mut gaurd = map.lock_keys(&[10, 11]).await.unwrap();
+        gaurd.insert(10, 10);
+        gaurd.insert(11, 11);
+        assert!(gaurd.remove(&12).is_none());
+    }
+
+    let stats = map.stats();
+    assert_eq!((stats.shards[0].inserts, stats.shards[0].removes), (5, 2));
+    assert_eq!(stats.operations(), 7);
+}
+
+#[tokio::test]
+async fn the_failure_rate_counts_the_skipped_shards(){
+    let map = ShardedMap::<u32, u32>::new(2);
+    let busy = map.shards()[1].lock().await;
+    for _ in 0..4{
+        assert_eq!(map.acquire_any_shard(Duration::from_secs(1)).await.unwrap().index(), 0);
+    }
+    drop(busy);
+
+    let stats = map.stats();
+    assert_eq!(stats.shards[0].try_lock_failure_rate(), 0.0);
+    assert_eq!(stats.shards[1].acquisitions, 0);
+    assert!(stats.shards[1].attempts > 0);
+    assert_eq!(stats.shards[1].try_lock_failure_rate(), 1.0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn the_latencies_of_every_thread_are_merged(){
+    let map = Arc::new(ShardedMap::<u32, u32>::new(2));
+    let writers = (0..8u32).map(|writer| {
+        let map = map.clone();
+        tokio::spawn(async move{
+            for n in 0..100{
+                map.insert(writer * 100 + n, n).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for writer in writers{
+        writer.await.unwrap();
+    }
+
+    let stats = map.stats();
+    let acquisitions = stats.shards.iter().map(|shard| shard.acquisitions).sum::<u64>();
+    assert_eq!(acquisitions, 800);
+    assert_eq!(stats.hold().len(), 800);
+    assert_eq!(stats.wait().len(), 800);
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use s3::{ShardedMap, Versioned};
+
+
+#[tokio::test]
+async fn a_lagging_subscriber_resyncs_from_a_snapshot(){
+    let map = Arc::new(ShardedMap::<u32, u32>::builder().shards(2).publish_capacity(2).build());
+    let mut subscriber = map.subscribe();
+    for key in 0..8u32{
+        map.insert(key, key * 10).await.unwrap();
+    }
+
+    // more stale publications than the channel can hold
+    for stale in 0..5u32{
+        map.publish(HashMap::from([(stale, Versioned::new(0, map.clock().now()))]));
+    }
+    assert_eq!(map.metrics().lag_resyncs(), 0);
+
+    let resynced = subscriber.recv().await.unwrap();
+    assert_eq!(map.metrics().lag_resyncs(), 1);
+    assert_eq!(*resynced, map.snapshot().await.unwrap());
+    assert_eq!(resynced.len(), 8);
+
+    // the subscriber has jumped to the tail of the channel
+    map.publish(HashMap::from([(42, Versioned::new(42, map.clock().now()))]));
+    let latest = subscriber.recv().await.unwrap();
+    assert_eq!(latest.get(&42).map(|v| v.value), Some(42));
+    assert_eq!(map.metrics().lag_resyncs(), 1);
+}
+
+#[tokio::test]
+async fn a_resync_keeps_the_home_copy_over_a_stale_stray_one(){
+    let map = Arc::new(ShardedMap::<u32, u32>::builder().shards(2).publish_capacity(2).copy_on_write(true).build());
+    let mut subscriber = map.subscribe();
+    let key = (0..).find(|key| map.shard_index(key) == 0).unwrap();
+    let stale = Versioned::new(1, map.clock().now());
+    map.insert(key, 2).await.unwrap();
+
+    // the home shard is busy so the stale copy lands in the other one and stays there
+    {
+        let _home = map.shards()[0].lock().await;
+        let mut gaurd = map.acquire_any_shard(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(gaurd.index(), 1);
+        gaurd.insert_versioned(key, stale);
+    }
+
+    for _ in 0..5{
+        map.publish(HashMap::new());
+    }
+    let resynced = subscriber.recv().await.unwrap();
+    assert_eq!(map.metrics().lag_resyncs(), 1);
+    assert_eq!(resynced.get(&key).map(|v| v.value), Some(2));
+    assert_eq!(map.view_snapshot().get(&key).map(|v| v.value), Some(2));
+}